@@ -1,7 +1,9 @@
 use chrono::prelude::*;
 use petgraph::dot::{Config, Dot};
+use petgraph::graph::EdgeReference;
 use petgraph::prelude::*;
 use petgraph_graphml::GraphMl;
+use std::borrow::Cow;
 use std::fmt;
 use std::fs;
 use std::io;
@@ -11,12 +13,15 @@ use std::path::{Path, PathBuf};
 use rand::Rng;
 use std::collections::hash_map::DefaultHasher;
 use std::collections::hash_map::RandomState;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::hash::{BuildHasher, Hash, Hasher};
 
 use serde::{Deserialize, Serialize};
 
-pub type Graph = petgraph::Graph<Node, (), petgraph::Undirected>;
+pub type Graph = petgraph::Graph<Node, Edge, petgraph::Undirected>;
+
+// graphs written before edges carried data
+type LegacyGraph = petgraph::Graph<Node, (), petgraph::Undirected>;
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub enum NodeType {
@@ -25,10 +30,201 @@ pub enum NodeType {
     Server,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Membership {
+    Join,
+    Invite,
+    Leave,
+    Ban,
+    Knock,
+}
+
+impl Membership {
+    // for merging memberships of the same user, lower is more active
+    fn activity_rank(self) -> u8 {
+        match self {
+            Membership::Join => 0,
+            Membership::Invite => 1,
+            Membership::Knock => 2,
+            Membership::Ban => 3,
+            Membership::Leave => 4,
+        }
+    }
+
+    pub const ALL: [Membership; 5] = [
+        Membership::Join,
+        Membership::Invite,
+        Membership::Leave,
+        Membership::Ban,
+        Membership::Knock,
+    ];
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum Edge {
+    /// between a user and a room, with the user's membership state in that room
+    Member(Membership),
+    /// between a user and its home server
+    Account,
+    /// between a room and the home server of a user with any membership state in that room
+    Participation,
+    /// between an upgraded room as source and the room replacing it as target
+    Successor,
+    /// between a space as source and one of the rooms it contains as target
+    SpaceChild,
+}
+
+impl fmt::Display for Edge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Edge::Member(membership) => write!(f, "{}", serde_name(membership)),
+            Edge::Account => write!(f, "account"),
+            Edge::Participation => write!(f, "participation"),
+            Edge::Successor => write!(f, "successor"),
+            Edge::SpaceChild => write!(f, "space_child"),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JoinRule {
+    Public,
+    Knock,
+    Invite,
+    Private,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HistoryVisibility {
+    Invited,
+    Joined,
+    Shared,
+    WorldReadable,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GuestAccess {
+    CanJoin,
+    Forbidden,
+}
+
+// the serde names match the values used in the matrix state events
+fn serde_name(value: &impl Serialize) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(name)) => name,
+        _ => unreachable!(),
+    }
+}
+
+/// Activity of a room, aggregated from a sample of its recent timeline.
+/// Senders are only counted, so nothing in here identifies a user.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub struct RoomActivity {
+    pub events_per_day: f64,
+    /// distinct senders per day, averaged over the sampled days
+    pub senders_per_day: f64,
+    /// share of state events among all sampled events, between 0 and 1
+    pub state_event_share: f64,
+}
+
+/// Room properties taken from the room state which do not identify the room.
+/// `None` means that the state event was not set or could not be understood.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct RoomAttributes {
+    pub room_version: Option<String>,
+    pub join_rule: Option<JoinRule>,
+    pub history_visibility: Option<HistoryVisibility>,
+    pub guest_access: Option<GuestAccess>,
+    /// `None` if it's unknown whether the room has an m.room.encryption event
+    pub encrypted: Option<bool>,
+    /// whether the room is a space, which organizes other rooms
+    #[serde(default)]
+    pub space: bool,
+    /// `None` if the timeline was not sampled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub activity: Option<RoomActivity>,
+}
+
+impl RoomAttributes {
+    /// key-value pairs of all known attributes, as used for the graph exports
+    pub fn to_pairs(&self) -> Vec<(&'static str, String)> {
+        let mut pairs = Vec::new();
+        if let Some(room_version) = &self.room_version {
+            pairs.push(("room_version", room_version.clone()));
+        }
+        if let Some(join_rule) = &self.join_rule {
+            pairs.push(("join_rule", serde_name(join_rule)));
+        }
+        if let Some(history_visibility) = &self.history_visibility {
+            pairs.push(("history_visibility", serde_name(history_visibility)));
+        }
+        if let Some(guest_access) = &self.guest_access {
+            pairs.push(("guest_access", serde_name(guest_access)));
+        }
+        if let Some(encrypted) = self.encrypted {
+            pairs.push(("encrypted", encrypted.to_string()));
+        }
+        pairs.push(("space", self.space.to_string()));
+        if let Some(activity) = &self.activity {
+            pairs.push(("events_per_day", format!("{:.2}", activity.events_per_day)));
+            pairs.push(("senders_per_day", format!("{:.2}", activity.senders_per_day)));
+            pairs.push(("state_event_share", format!("{:.3}", activity.state_event_share)));
+        }
+        pairs
+    }
+}
+
+/// What kind of account a user is, as far as it can be told from the user id.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum UserClass {
+    Native,
+    /// puppet of a user on another network, with the bridge family, e.g. "irc" or "discord"
+    Bridged(String),
+    Bot,
+    Guest,
+}
+
+impl UserClass {
+    /// key-value pairs of the class, as used for the graph exports
+    pub fn to_pairs(&self) -> Vec<(&'static str, String)> {
+        match self {
+            UserClass::Native => vec![("user_class", "native".to_string())],
+            UserClass::Bridged(bridge) => vec![
+                ("user_class", "bridged".to_string()),
+                ("bridge", bridge.clone()),
+            ],
+            UserClass::Bot => vec![("user_class", "bot".to_string())],
+            UserClass::Guest => vec![("user_class", "guest".to_string())],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Node {
     pub kind: NodeType,
     pub id: u64,
+    // only set for room nodes. Graphs written before attributes were recorded don't have them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<RoomAttributes>,
+    // only set for user nodes. Graphs written before users were classified don't have it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<UserClass>,
+}
+
+impl Node {
+    /// key-value pairs of all known node attributes, as used for the graph exports
+    pub fn attributes(&self) -> Vec<(&'static str, String)> {
+        match (&self.room, &self.user) {
+            (Some(room), _) => room.to_pairs(),
+            (None, Some(user)) => user.to_pairs(),
+            (None, None) => Vec::new(),
+        }
+    }
 }
 
 impl fmt::Display for Node {
@@ -41,13 +237,62 @@ impl fmt::Display for Node {
     }
 }
 
-// hack around the type signature of Dot::fmt which requires both node and edge data types to implement Display.
-// But as I have no edge data, I want to use (), which does not implement Display, though.
-// Convert to this type before using Dot::fmt. As I use the EdgeNoLabel option of Dot::fmt, unreachable! is enough.
-struct NoEdgeData;
-impl fmt::Display for NoEdgeData {
-    fn fmt(&self, _f: &mut fmt::Formatter) -> fmt::Result {
-        unreachable!();
+// node label for the dot export, which has no separate node attributes
+struct DotNodeLabel<'a>(&'a Node);
+impl<'a> fmt::Display for DotNodeLabel<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)?;
+        let attributes = self.0.attributes();
+        if !attributes.is_empty() {
+            let attributes: Vec<String> = attributes
+                .into_iter()
+                .map(|(key, value)| format!("{}: {}", key, value))
+                .collect();
+            write!(f, " ({})", attributes.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+fn graphml_node_weights(node: &Node) -> Vec<(Cow<'static, str>, Cow<'_, str>)> {
+    let mut weights = vec![(Cow::Borrowed("weight"), Cow::Owned(node.to_string()))];
+    weights.extend(
+        node.attributes()
+            .into_iter()
+            .map(|(key, value)| (Cow::Borrowed(key), Cow::Owned(value))),
+    );
+    weights
+}
+
+/// A hash key that, unlike `RandomState`, can be persisted,
+/// e.g. to continue an interrupted crawl with the same pseudonyms.
+// DefaultHasher uses fixed SipHash keys, so the key is hashed as a prefix of every value instead.
+// As std does not guarantee a stable DefaultHasher algorithm across Rust releases,
+// a persisted key should only be used with the same build that created it.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct HashKey {
+    k0: u64,
+    k1: u64,
+}
+
+impl HashKey {
+    pub fn random() -> Self {
+        let mut rng = rand::thread_rng();
+        HashKey {
+            k0: rng.gen(),
+            k1: rng.gen(),
+        }
+    }
+}
+
+impl BuildHasher for HashKey {
+    type Hasher = DefaultHasher;
+
+    fn build_hasher(&self) -> DefaultHasher {
+        let mut hasher = DefaultHasher::new();
+        self.k0.hash(&mut hasher);
+        self.k1.hash(&mut hasher);
+        hasher
     }
 }
 
@@ -58,10 +303,127 @@ fn hash_with_salt(builder: &dyn BuildHasher<Hasher = DefaultHasher>, x: &impl Ha
     hasher.finish()
 }
 
+/// Maps rooms to their node ids in an anonymized graph, so that rooms can be removed from it later on,
+/// e.g. when the bot gets kicked from a room after the graph was written.
+/// Rooms are identified by a keyed hash of their room id, which can only be computed by knowing the room id.
+/// This stays local, outside of the published data/graphs, as anyone knowing the key could hash
+/// public room ids to find their nodes. Deleting it makes the corresponding graph irreversibly anonymous.
+#[derive(Serialize, Deserialize)]
+pub struct RoomFingerprints {
+    key: HashKey,
+    rooms: HashMap<u64, u64>,
+}
+
+impl RoomFingerprints {
+    pub fn new() -> Self {
+        RoomFingerprints {
+            key: HashKey::random(),
+            rooms: HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, room: &impl Hash, node_id: u64) {
+        let fingerprint = hash_with_salt(&self.key, room, 0);
+        self.rooms.insert(fingerprint, node_id);
+    }
+
+    /// removes the given room, returning the id of its node if it was known
+    pub fn remove(&mut self, room: &impl Hash) -> Option<u64> {
+        let fingerprint = hash_with_salt(&self.key, room, 0);
+        self.rooms.remove(&fingerprint)
+    }
+}
+
+impl Default for RoomFingerprints {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// where the room fingerprints of the graph in the given graph directory are stored
+fn room_fingerprints_path(graph_dir: &Path) -> PathBuf {
+    let name = graph_dir.file_name().expect("graph directory without name");
+    Path::new("data/fingerprints").join(name).with_extension("json")
+}
+
+// older graph directories contain their room fingerprints
+static LEGACY_FINGERPRINTS_FILE: &str = "room_fingerprints.json";
+
+/// reads the room fingerprints of the graph in the given graph directory
+pub fn read_room_fingerprints<P: AsRef<Path>>(graph_dir: P) -> io::Result<RoomFingerprints> {
+    let file = fs::File::open(room_fingerprints_path(graph_dir.as_ref()))
+        .or_else(|_| fs::File::open(graph_dir.as_ref().join(LEGACY_FINGERPRINTS_FILE)))?;
+    let reader = io::BufReader::new(file);
+    Ok(serde_json::from_reader(reader)?)
+}
+
+/// writes the room fingerprints of the graph in the given graph directory to data/fingerprints,
+/// removing fingerprints that older versions left in the graph directory
+pub fn write_room_fingerprints<P: AsRef<Path>>(
+    fingerprints: &RoomFingerprints,
+    graph_dir: P,
+) -> Result<(), serde_json::Error> {
+    let path = room_fingerprints_path(graph_dir.as_ref());
+    fs::create_dir_all(path.parent().unwrap()).expect("Could not create room fingerprints directory");
+    let file = fs::File::create(path).expect("Could not create room fingerprints file");
+    let writer = io::BufWriter::new(file);
+    serde_json::to_writer(writer, fingerprints)?;
+
+    let legacy_path = graph_dir.as_ref().join(LEGACY_FINGERPRINTS_FILE);
+    if legacy_path.exists() {
+        fs::remove_file(legacy_path).expect("Could not remove room fingerprints from graph directory");
+    }
+    Ok(())
+}
+
+/// all graph directories written by graph_dir, oldest first
+pub fn stored_graph_dirs() -> io::Result<Vec<PathBuf>> {
+    let graphs = Path::new("data/graphs");
+    if !graphs.exists() {
+        return Ok(Vec::new());
+    }
+    let mut dirs = Vec::new();
+    for entry in fs::read_dir(graphs)? {
+        let path = entry?.path();
+        if path.is_dir() && path.join("graph.json").exists() {
+            dirs.push(path);
+        }
+    }
+    dirs.sort();
+    Ok(dirs)
+}
+
 pub fn read_graph<P: AsRef<Path>>(path: P) -> Result<Graph, serde_json::Error> {
     let file = fs::File::open(path).unwrap();
     let reader = io::BufReader::new(file);
-    serde_json::from_reader(reader)
+    let value: serde_json::Value = serde_json::from_reader(reader)?;
+    match serde_json::from_value(value.clone()) {
+        Ok(graph) => Ok(graph),
+        Err(e) => match serde_json::from_value(value) {
+            Ok(legacy_graph) => Ok(upgrade_legacy_graph(&legacy_graph)),
+            Err(_) => Err(e),
+        },
+    }
+}
+
+// Legacy graphs only contained joined users,
+// so the edge type can be reconstructed from the kinds of the connected nodes.
+fn upgrade_legacy_graph(graph: &LegacyGraph) -> Graph {
+    graph.map(
+        |_, node| node.clone(),
+        |idx, _| {
+            let (a, b) = graph.edge_endpoints(idx).unwrap();
+            match (graph[a].kind, graph[b].kind) {
+                (NodeType::User, NodeType::Room) | (NodeType::Room, NodeType::User) => {
+                    Edge::Member(Membership::Join)
+                },
+                (NodeType::User, NodeType::Server) | (NodeType::Server, NodeType::User) => {
+                    Edge::Account
+                },
+                _ => Edge::Participation,
+            }
+        },
+    )
 }
 
 pub fn graph_dir() -> PathBuf {
@@ -86,7 +448,8 @@ pub fn write_graph<P: AsRef<Path>>(graph: &Graph, dir: P) -> Result<(), serde_js
 pub fn export_graph_to_graphml<P: AsRef<Path>>(graph: &Graph, dir: P) -> io::Result<()> {
     let graphml = GraphMl::new(&graph)
         .pretty_print(true)
-        .export_node_weights_display();
+        .export_edge_weights_display()
+        .export_node_weights(Box::new(graphml_node_weights));
     let file = fs::File::create(dir.as_ref().join("graph.graphml"))
         .expect("Could not create graph/graph.graphml file");
     let writer = io::BufWriter::new(file);
@@ -94,147 +457,458 @@ pub fn export_graph_to_graphml<P: AsRef<Path>>(graph: &Graph, dir: P) -> io::Res
 }
 
 pub fn export_graph_to_dot<P: AsRef<Path>>(graph: &Graph, dir: P) -> io::Result<()> {
-    let no_edge_data = graph.map(|_, node| node, |_, _| NoEdgeData);
-    let exported_graph = Dot::with_config(&no_edge_data, &[Config::EdgeNoLabel]);
+    let labeled_graph = graph.map(|_, node| DotNodeLabel(node), |_, edge| *edge);
+    let exported_graph = Dot::with_config(&labeled_graph, &[Config::EdgeNoLabel]);
     let file = fs::File::create(dir.as_ref().join("graph.dot"))
         .expect("Could not create graph/graph.dot file");
     let mut buffer = io::BufWriter::new(file);
     write!(&mut buffer, "{}", exported_graph)
 }
 
+/// Anonymizes node ids by hashing them together with a random salt, using a random hash function.
+/// Both are thrown away together with the anonymizer.
+pub struct Anonymizer {
+    hash_key: RandomState,
+    salt: u64,
+}
+
+impl Anonymizer {
+    pub fn new() -> Self {
+        let mut rng = rand::thread_rng();
+        Anonymizer {
+            hash_key: RandomState::new(),
+            salt: rng.gen::<u64>(),
+        }
+    }
+
+    pub fn id(&self, id: u64) -> u64 {
+        hash_with_salt(&self.hash_key, &id, self.salt)
+    }
+
+    /// keeps the node indices, only the node ids are replaced
+    pub fn anonymize(&self, graph: &Graph) -> Graph {
+        graph.map(
+            |_, node| Node {
+                kind: node.kind,
+                id: self.id(node.id),
+                room: node.room.clone(),
+                user: node.user.clone(),
+            },
+            |_, edge| *edge,
+        )
+    }
+}
+
+impl Default for Anonymizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub fn anonymize_graph(graph: Graph) -> Graph {
-    let hash_key = RandomState::new();
-    let mut rng = rand::thread_rng();
-    let salt = rng.gen::<u64>();
-    graph.map(
-        |_, node| Node {
-            kind: node.kind,
-            id: hash_with_salt(&hash_key, &node.id, salt),
-        },
-        |_, _| (),
-    )
+    Anonymizer::new().anonymize(&graph)
+}
+
+/// The difference between two graphs with the same node ids.
+/// Removed nodes are only counted, their ids are in none of the published graphs
+/// and anonymized with a later salt they would match nothing.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct GraphDelta {
+    pub added_rooms: Vec<u64>,
+    pub removed_rooms: usize,
+    pub added_users: Vec<u64>,
+    pub removed_users: usize,
+    pub added_servers: Vec<u64>,
+    pub removed_servers: usize,
+    /// user-room pairs whose membership state was added, removed or changed
+    pub changed_memberships: usize,
+}
+
+impl GraphDelta {
+    /// anonymizes the node ids in the same way as the graph written together with this delta
+    pub fn anonymize(&self, anonymizer: &Anonymizer) -> GraphDelta {
+        let anonymize = |ids: &Vec<u64>| ids.iter().map(|&id| anonymizer.id(id)).collect();
+        GraphDelta {
+            added_rooms: anonymize(&self.added_rooms),
+            removed_rooms: self.removed_rooms,
+            added_users: anonymize(&self.added_users),
+            removed_users: self.removed_users,
+            added_servers: anonymize(&self.added_servers),
+            removed_servers: self.removed_servers,
+            changed_memberships: self.changed_memberships,
+        }
+    }
+}
+
+fn node_ids(graph: &Graph, kind: NodeType) -> HashSet<u64> {
+    graph
+        .node_indices()
+        .filter(|&idx| graph[idx].kind == kind)
+        .map(|idx| graph[idx].id)
+        .collect()
+}
+
+fn memberships(graph: &Graph) -> HashMap<(u64, u64), Membership> {
+    graph
+        .edge_references()
+        .filter_map(|edge| match edge.weight() {
+            Edge::Member(membership) => {
+                let (a, b) = (graph[edge.source()].id, graph[edge.target()].id);
+                if graph[edge.source()].kind == NodeType::User {
+                    Some(((a, b), *membership))
+                } else {
+                    Some(((b, a), *membership))
+                }
+            },
+            _ => None,
+        })
+        .collect()
+}
+
+pub fn graph_delta(old: &Graph, new: &Graph) -> GraphDelta {
+    let difference = |kind: NodeType| {
+        let old_ids = node_ids(old, kind);
+        let new_ids = node_ids(new, kind);
+        let mut added: Vec<u64> = new_ids.difference(&old_ids).cloned().collect();
+        added.sort();
+        (added, old_ids.difference(&new_ids).count())
+    };
+    let (added_rooms, removed_rooms) = difference(NodeType::Room);
+    let (added_users, removed_users) = difference(NodeType::User);
+    let (added_servers, removed_servers) = difference(NodeType::Server);
+
+    let old_memberships = memberships(old);
+    let new_memberships = memberships(new);
+    let changed_memberships = new_memberships
+        .iter()
+        .filter(|(pair, membership)| old_memberships.get(*pair) != Some(*membership))
+        .count()
+        + old_memberships
+            .keys()
+            .filter(|pair| !new_memberships.contains_key(*pair))
+            .count();
+
+    GraphDelta {
+        added_rooms,
+        removed_rooms,
+        added_users,
+        removed_users,
+        added_servers,
+        removed_servers,
+        changed_memberships,
+    }
+}
+
+pub fn write_delta<P: AsRef<Path>>(delta: &GraphDelta, dir: P) -> Result<(), serde_json::Error> {
+    let path = dir.as_ref().join("delta.json");
+    let file = fs::File::create(path).expect("Could not create delta file");
+    let writer = io::BufWriter::new(file);
+    serde_json::to_writer(writer, delta)
 }
 
-fn is_wellformed_node(graph: &Graph, idx: NodeIndex) -> bool {
+fn other_endpoint(edge: EdgeReference<Edge>, idx: NodeIndex) -> NodeIndex {
+    if edge.source() == idx {
+        edge.target()
+    } else {
+        edge.source()
+    }
+}
+
+/// neighbors of the given node connected through an edge of the given kind
+fn neighbors_by<'a>(
+    graph: &'a Graph,
+    idx: NodeIndex,
+    is_wanted: impl Fn(&Edge) -> bool + 'a,
+) -> impl Iterator<Item = NodeIndex> + 'a {
+    graph
+        .edges(idx)
+        .filter(move |edge| is_wanted(edge.weight()))
+        .map(move |edge| other_endpoint(edge, idx))
+}
+
+/// rooms of a user or users of a room with one of the given membership states
+fn members<'a>(
+    graph: &'a Graph,
+    idx: NodeIndex,
+    states: &'a [Membership],
+) -> impl Iterator<Item = NodeIndex> + 'a {
+    neighbors_by(graph, idx, move |edge| match edge {
+        Edge::Member(membership) => states.contains(membership),
+        _ => false,
+    })
+}
+
+/// users of a server or the server of a user
+fn accounts<'a>(graph: &'a Graph, idx: NodeIndex) -> impl Iterator<Item = NodeIndex> + 'a {
+    neighbors_by(graph, idx, |edge| *edge == Edge::Account)
+}
+
+/// rooms of a server or servers of a room
+fn participations<'a>(graph: &'a Graph, idx: NodeIndex) -> impl Iterator<Item = NodeIndex> + 'a {
+    neighbors_by(graph, idx, |edge| *edge == Edge::Participation)
+}
+
+/// Removes the rooms with the given node ids, together with all users and servers that were only
+/// connected to the graph through them. Returns the number of removed rooms.
+pub fn remove_rooms(graph: &mut Graph, room_ids: &HashSet<u64>) -> usize {
+    let room_count = graph.node_count();
+    graph.retain_nodes(|graph, idx| {
+        !(graph[idx].kind == NodeType::Room && room_ids.contains(&graph[idx].id))
+    });
+    let removed_rooms = room_count - graph.node_count();
+    if removed_rooms > 0 {
+        // servers are only connected through their users, so users have to go first
+        graph.retain_nodes(|graph, idx| {
+            graph[idx].kind != NodeType::User
+                || members(&graph, idx, &Membership::ALL).next().is_some()
+        });
+        graph.retain_nodes(|graph, idx| {
+            graph[idx].kind != NodeType::Server
+                || (accounts(&graph, idx).next().is_some()
+                    && participations(&graph, idx).next().is_some())
+        });
+    }
+    removed_rooms
+}
+
+// Source and target of undirected edges are only kept by the edge endpoints,
+// the edge references of Graph::edges might report them swapped.
+fn successor(graph: &Graph, room_idx: NodeIndex) -> Option<NodeIndex> {
+    graph
+        .edges(room_idx)
+        .filter(|edge| *edge.weight() == Edge::Successor)
+        .filter_map(|edge| graph.edge_endpoints(edge.id()))
+        .find(|&(source, _)| source == room_idx)
+        .map(|(_, target)| target)
+}
+
+// the end of the upgrade chain the given room is part of
+fn newest_room(graph: &Graph, room_idx: NodeIndex) -> NodeIndex {
+    let mut current = room_idx;
+    let mut seen = HashSet::new();
+    // upgrade chains should not have cycles, but room state is controlled by others
+    while seen.insert(current) {
+        match successor(graph, current) {
+            Some(next) => current = next,
+            None => break,
+        }
+    }
+    current
+}
+
+/// Merges each chain of upgraded rooms into its newest room,
+/// so that the users of a chain are counted once for the logical room.
+/// If a user has memberships in several rooms of a chain, the most active one is kept, e.g. join over leave.
+pub fn collapse_upgrade_chains(graph: &Graph) -> Graph {
+    let representative = |idx: NodeIndex| match graph[idx].kind {
+        NodeType::Room => newest_room(graph, idx),
+        _ => idx,
+    };
+
+    let mut collapsed = Graph::new_undirected();
+    let mut new_indices = HashMap::new();
+    for idx in graph.node_indices() {
+        if representative(idx) == idx {
+            new_indices.insert(idx, collapsed.add_node(graph[idx].clone()));
+        }
+    }
+    let new_index = |idx: NodeIndex| new_indices[&representative(idx)];
+
+    // ordered, so that the edge order does not depend on the hash map's random state
+    let mut memberships = BTreeMap::new();
+    for edge in graph.edge_references() {
+        let (a, b) = (new_index(edge.source()), new_index(edge.target()));
+        match *edge.weight() {
+            Edge::Successor => {},
+            // a space containing several rooms of a chain contains the logical room once
+            Edge::SpaceChild => {
+                if a != b {
+                    collapsed.update_edge(a, b, Edge::SpaceChild);
+                }
+            },
+            Edge::Member(membership) => {
+                let user_room = if collapsed[a].kind == NodeType::User { (a, b) } else { (b, a) };
+                let merged = memberships.entry(user_room).or_insert(membership);
+                if membership.activity_rank() < merged.activity_rank() {
+                    *merged = membership;
+                }
+            },
+            other => {
+                collapsed.update_edge(a, b, other);
+            },
+        }
+    }
+    for ((user_idx, room_idx), membership) in memberships {
+        collapsed.add_edge(user_idx, room_idx, Edge::Member(membership));
+    }
+    collapsed
+}
+
+fn is_wellformed_node(graph: &Graph, idx: NodeIndex, states: &[Membership]) -> bool {
     let is_wellformed = match graph[idx].kind {
         NodeType::User => {
             // a user needs exactly one HS and be a member of at least one room.
             // This should be impossible, as we get the HS from the user id and find users through a room.
-            graph
-                .neighbors(idx)
-                .filter(|&neighbor_idx| graph[neighbor_idx].kind == NodeType::Server)
-                .count() == 1
-                && graph
-                    .neighbors(idx)
-                    .any(|neighbor_idx| graph[neighbor_idx].kind == NodeType::Room)
+            accounts(graph, idx).count() == 1 && members(graph, idx, states).next().is_some()
         },
         NodeType::Room => {
             // A room needs at least one user and at least one server. Could be caused by ignore patterns.
             // As those disconnected rooms do nothing for the simulation an only dillute the results, I should remove them.
-            graph
-                .neighbors(idx)
-                .any(|neighbor_idx| graph[neighbor_idx].kind == NodeType::User)
-                && graph
-                    .neighbors(idx)
-                    .any(|neighbor_idx| graph[neighbor_idx].kind == NodeType::Server)
+            members(graph, idx, states).next().is_some()
+                && participations(graph, idx).next().is_some()
         },
         NodeType::Server => {
             // A server needs at least one user and at least one room.
             // This should be impossible, as we only can see servers through a user in a room.
-            graph
-                .neighbors(idx)
-                .any(|neighbor_idx| graph[neighbor_idx].kind == NodeType::User)
-                && graph
-                    .neighbors(idx)
-                    .any(|neighbor_idx| graph[neighbor_idx].kind == NodeType::Room)
+            accounts(graph, idx).next().is_some() && participations(graph, idx).next().is_some()
         },
     };
     if !is_wellformed {
         eprintln!(
-            "malformed node: {}. neighbors: {} users, {} rooms, {} servers.",
+            "malformed node: {}. {} memberships in the given states, {} accounts, {} participations.",
             graph[idx],
-            graph
-                .neighbors(idx)
-                .filter(|&neighbor_idx| graph[neighbor_idx].kind == NodeType::User)
-                .count(),
-            graph
-                .neighbors(idx)
-                .filter(|&neighbor_idx| graph[neighbor_idx].kind == NodeType::Room)
-                .count(),
-            graph
-                .neighbors(idx)
-                .filter(|&neighbor_idx| graph[neighbor_idx].kind == NodeType::Server)
-                .count(),
+            members(graph, idx, states).count(),
+            accounts(graph, idx).count(),
+            participations(graph, idx).count(),
         );
     }
     is_wellformed
 }
 
-pub fn is_wellformed_graph(graph: &Graph) -> bool {
+/// checks the graph structure, counting only memberships in the given states
+pub fn is_wellformed_graph(graph: &Graph, states: &[Membership]) -> bool {
     graph
         .node_indices()
-        .all(|idx| is_wellformed_node(graph, idx))
+        .all(|idx| is_wellformed_node(graph, idx, states))
 }
 
-// returns map from server id to number of users and rooms
-pub fn users_rooms_per_server_distribution(graph: &Graph) -> HashMap<u64, (usize, usize)> {
+// returns map from server id to number of users and rooms,
+// counting only users with at least one membership in the given states and the rooms of those memberships
+pub fn users_rooms_per_server_distribution(
+    graph: &Graph,
+    states: &[Membership],
+) -> HashMap<u64, (usize, usize)> {
     graph
         .node_indices()
         .filter(|idx| graph[*idx].kind == NodeType::Server)
         .map(|idx| {
-            (
-                graph[idx].id,
-                (
-                    graph
-                        .neighbors(idx)
-                        .filter(|&neighbor_idx| graph[neighbor_idx].kind == NodeType::User)
-                        .count(),
-                    graph
-                        .neighbors(idx)
-                        .filter(|&neighbor_idx| graph[neighbor_idx].kind == NodeType::Room)
-                        .count(),
-                ),
-            )
+            let users: Vec<NodeIndex> = accounts(graph, idx)
+                .filter(|&user_idx| members(graph, user_idx, states).next().is_some())
+                .collect();
+            let rooms: HashSet<NodeIndex> = users
+                .iter()
+                .flat_map(|&user_idx| members(graph, user_idx, states))
+                .collect();
+            (graph[idx].id, (users.len(), rooms.len()))
         })
         .collect()
 }
 
-// returns map from room id to number of users and servers
-pub fn users_servers_per_room_distribution(graph: &Graph) -> HashMap<u64, (usize, usize)> {
+// returns map from room id to number of users and servers,
+// counting only users with a membership in the given states and the servers of those users
+pub fn users_servers_per_room_distribution(
+    graph: &Graph,
+    states: &[Membership],
+) -> HashMap<u64, (usize, usize)> {
     graph
         .node_indices()
         .filter(|idx| graph[*idx].kind == NodeType::Room)
         .map(|idx| {
-            (
-                graph[idx].id,
-                (
-                    graph
-                        .neighbors(idx)
-                        .filter(|&neighbor_idx| graph[neighbor_idx].kind == NodeType::User)
-                        .count(),
-                    graph
-                        .neighbors(idx)
-                        .filter(|&neighbor_idx| graph[neighbor_idx].kind == NodeType::Server)
-                        .count(),
-                ),
-            )
+            let users: Vec<NodeIndex> = members(graph, idx, states).collect();
+            let servers: HashSet<NodeIndex> = users
+                .iter()
+                .flat_map(|&user_idx| accounts(graph, user_idx))
+                .collect();
+            (graph[idx].id, (users.len(), servers.len()))
         })
         .collect()
 }
 
-// returns map from user id to number of rooms (servers per user makes no sense as that's 1:n)
-pub fn rooms_per_user_distribution(graph: &Graph) -> HashMap<u64, usize> {
+// returns map from user id to number of rooms with a membership in the given states
+// (servers per user makes no sense as that's 1:n)
+pub fn rooms_per_user_distribution(graph: &Graph, states: &[Membership]) -> HashMap<u64, usize> {
     graph
         .node_indices()
         .filter(|idx| graph[*idx].kind == NodeType::User)
-        .map(|idx| {
-            (
-                graph[idx].id,
-                graph
-                    .neighbors(idx)
-                    .filter(|&neighbor_idx| graph[neighbor_idx].kind == NodeType::Room)
-                    .count(),
-            )
-        })
+        .map(|idx| (graph[idx].id, members(graph, idx, states).count()))
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(kind: NodeType, id: u64) -> Node {
+        Node {
+            kind,
+            id,
+            room: None,
+            user: None,
+        }
+    }
+
+    #[test]
+    fn graph_delta_counts_removed_nodes_and_changed_memberships() {
+        let mut old = Graph::new_undirected();
+        let server = old.add_node(node(NodeType::Server, 100));
+        let user = old.add_node(node(NodeType::User, 10));
+        let room_1 = old.add_node(node(NodeType::Room, 1));
+        let room_2 = old.add_node(node(NodeType::Room, 2));
+        old.add_edge(user, server, Edge::Account);
+        old.add_edge(user, room_1, Edge::Member(Membership::Join));
+        old.add_edge(user, room_2, Edge::Member(Membership::Invite));
+
+        let mut new = Graph::new_undirected();
+        let server = new.add_node(node(NodeType::Server, 100));
+        let user = new.add_node(node(NodeType::User, 10));
+        let room_2 = new.add_node(node(NodeType::Room, 2));
+        let room_3 = new.add_node(node(NodeType::Room, 3));
+        new.add_edge(user, server, Edge::Account);
+        new.add_edge(user, room_2, Edge::Member(Membership::Join));
+        new.add_edge(user, room_3, Edge::Member(Membership::Join));
+
+        let delta = graph_delta(&old, &new);
+        assert_eq!(delta.added_rooms, vec![3]);
+        assert_eq!(delta.removed_rooms, 1);
+        assert!(delta.added_users.is_empty());
+        assert_eq!(delta.removed_users, 0);
+        assert!(delta.added_servers.is_empty());
+        assert_eq!(delta.removed_servers, 0);
+        // room 1 left, room 2 changed from invite to join, room 3 joined
+        assert_eq!(delta.changed_memberships, 3);
+    }
+
+    #[test]
+    fn collapsing_upgrade_chains_keeps_the_most_active_membership() {
+        let mut graph = Graph::new_undirected();
+        let server = graph.add_node(node(NodeType::Server, 100));
+        let alice = graph.add_node(node(NodeType::User, 10));
+        let bob = graph.add_node(node(NodeType::User, 11));
+        let old_room = graph.add_node(node(NodeType::Room, 1));
+        let new_room = graph.add_node(node(NodeType::Room, 2));
+        graph.add_edge(old_room, new_room, Edge::Successor);
+        graph.add_edge(alice, server, Edge::Account);
+        graph.add_edge(bob, server, Edge::Account);
+        graph.add_edge(alice, old_room, Edge::Member(Membership::Join));
+        graph.add_edge(alice, new_room, Edge::Member(Membership::Leave));
+        graph.add_edge(bob, old_room, Edge::Member(Membership::Join));
+        graph.add_edge(server, old_room, Edge::Participation);
+        graph.add_edge(server, new_room, Edge::Participation);
+
+        let collapsed = collapse_upgrade_chains(&graph);
+        let find = |kind: NodeType, id: u64| {
+            collapsed
+                .node_indices()
+                .find(|&idx| collapsed[idx].kind == kind && collapsed[idx].id == id)
+        };
+        assert_eq!(find(NodeType::Room, 1), None);
+        let room = find(NodeType::Room, 2).unwrap();
+        let membership = |user| {
+            let edge = collapsed.find_edge(find(NodeType::User, user).unwrap(), room).unwrap();
+            collapsed[edge]
+        };
+        assert_eq!(membership(10), Edge::Member(Membership::Join));
+        assert_eq!(membership(11), Edge::Member(Membership::Join));
+        assert_eq!(collapsed.edges(room).count(), 3);
+    }
+}
@@ -0,0 +1,36 @@
+// endpoints, or variants of them, that ruma-client-api doesn't support yet
+
+/// [POST /_matrix/client/r0/join/{roomIdOrAlias}](https://matrix.org/docs/spec/client_server/r0.6.0#post-matrix-client-r0-join-roomidoralias)
+/// with the `server_name` parameter, so that rooms only known by their id can be joined over federation.
+// The spec allows to give several servers, but serde_urlencoded can't serialize repeated parameters.
+pub mod join_room_via {
+    use ruma_api::ruma_api;
+    use ruma_client::identifiers::{RoomId, RoomIdOrAliasId};
+    use serde::{Deserialize, Serialize};
+
+    ruma_api! {
+        metadata {
+            description: "Join a room using its ID or one of its aliases, through the given server.",
+            method: POST,
+            name: "join_room_via",
+            path: "/_matrix/client/r0/join/:room_id_or_alias",
+            rate_limited: true,
+            requires_authentication: true,
+        }
+
+        request {
+            /// The room to join.
+            #[ruma_api(path)]
+            pub room_id_or_alias: RoomIdOrAliasId,
+            /// A server that is in the room, which the homeserver asks to join it.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            #[ruma_api(query)]
+            pub server_name: Option<String>,
+        }
+
+        response {
+            /// The room that the user joined.
+            pub room_id: RoomId,
+        }
+    }
+}
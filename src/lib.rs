@@ -1,9 +1,15 @@
-use std::collections::{HashMap, HashSet};
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::TryFrom;
 use std::fmt;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
 use std::iter::FromIterator;
+use std::path::{Path, PathBuf};
 use std::time;
 
+use futures::stream::{self, StreamExt};
 use futures_timer::Delay;
 use ruma_client::api::r0;
 use ruma_client::{
@@ -22,11 +28,20 @@ use lazy_static::lazy_static;
 
 use rand::Rng;
 use std::collections::hash_map::DefaultHasher;
-use std::collections::hash_map::RandomState;
 use std::hash::{BuildHasher, Hash, Hasher};
 use std::sync::atomic::{AtomicUsize, Ordering};
 
-use matrixgraph::{Node, NodeType};
+use sha2::{Digest, Sha256};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+mod api;
+mod pacing;
+
+use matrixgraph::{
+    Edge, HashKey, Membership, Node, NodeType, RoomActivity, RoomAttributes, UserClass,
+};
 
 // if we continue to use the same access token,
 // we need to try to have unique txnids.
@@ -39,18 +54,6 @@ lazy_static! {
     };
 }
 
-// try best to avoid rate limiting for federation requests for resolve_alias and join_room
-// 2500 rooms * 2s = 1.5 Days
-// 2500 rooms * 5s = 3 Days
-// more info on rate limiting:
-// https://github.com/matrix-org/synapse/blob/9bba6ebaa903a81cd94fada114aa71e20b685adb/synapse/config/ratelimiting.py#L30
-// in case of room_crawl, it's only my own home server rate limiting,
-// as this does not require federation requests, I should be able to raise that limit arbitrarily
-// 2500 rooms * 0.2 = 8 minutes
-// 5 seconds resulted in load factor of 4, spacing out to have more time for the computation
-static ROOM_JOIN_DELAY: time::Duration = time::Duration::from_millis(64000);
-static ROOM_CRAWL_DELAY: time::Duration = time::Duration::from_millis(500);
-
 // this is essentially a ruma_identifiers::UserId without localpart,
 // to profit from the UserId parsing rules and being easily able to differentiate servers if they
 // have  non-standard port numbers
@@ -79,42 +82,51 @@ pub async fn send_message(
     room_id: RoomId,
     message: String,
 ) -> Result<EventId, ruma_client::Error> {
-    let response = client.request(
+    send_text(&pacing::LOCAL, client, room_id, message).await
+}
+
+async fn send_text(
+    pacer: &pacing::Pacer,
+    client: HttpsClient,
+    room_id: RoomId,
+    message: String,
+) -> Result<EventId, ruma_client::Error> {
+    let response = pacer.request(|| client.request(
         r0::send::send_message_event::Request {
-            room_id: room_id,
+            room_id: room_id.clone(),
             event_type: EventType::RoomMessage,
             txn_id: TXN_ID.fetch_add(1, Ordering::Relaxed).to_string(),
             data: MessageEventContent::Text(TextMessageEventContent {
-                body: message,
+                body: message.clone(),
                 format: None,
                 formatted_body: None,
                 relates_to: None,
             }),
         }
-    ).await?;
+    )).await?;
     Ok(response.event_id)
 }
 
-async fn joined_rooms(
+pub async fn joined_rooms(
     client: HttpsClient,
 ) -> Result<Vec<RoomId>, ruma_client::Error> {
-    let response = client.request(
-        r0::membership::joined_rooms::Request {}).await?;
+    let response = pacing::LOCAL.request(|| client.request(
+        r0::membership::joined_rooms::Request {})).await?;
     Ok(response.joined_rooms)
 }
 
-async fn sync_rooms(
-    client: HttpsClient,
-) -> Result<r0::sync::sync_events::Rooms, ruma_client::Error> {
-    use r0::filter;
-    let filter_all = filter::Filter {
+fn filter_all() -> r0::filter::Filter {
+    r0::filter::Filter {
         not_types: vec!["*".to_owned()],
         limit: None,
         senders: None,
         types: None,
         not_senders: Vec::new(),
-    };
-    let filter_all_events = filter::RoomEventFilter {
+    }
+}
+
+fn filter_all_events() -> r0::filter::RoomEventFilter {
+    r0::filter::RoomEventFilter {
         not_types: vec!["*".to_owned()],
         limit: None,
         types: None,
@@ -122,7 +134,14 @@ async fn sync_rooms(
         not_rooms: Vec::new(),
         senders: None,
         not_senders: Vec::new(),
-    };
+    }
+}
+
+/// delivers the rooms together with the sync token for later incremental syncs
+async fn sync_rooms(
+    client: HttpsClient,
+) -> Result<(r0::sync::sync_events::Rooms, String), ruma_client::Error> {
+    use r0::filter;
     // TODO: the current version of the spec actually has a StateFilter type
     // -> add that in ruma
     let only_canonical_alias = filter::RoomEventFilter {
@@ -138,9 +157,9 @@ async fn sync_rooms(
     };
     let room_filter = filter::RoomFilter {
         include_leave: Some(true),
-        account_data: Some(filter_all_events.clone()),
-        timeline: Some(filter_all_events.clone()),
-        ephemeral: Some(filter_all_events.clone()),
+        account_data: Some(filter_all_events()),
+        timeline: Some(filter_all_events()),
+        ephemeral: Some(filter_all_events()),
         state: Some(only_canonical_alias),
         not_rooms: Vec::new(),
         rooms: None,
@@ -148,375 +167,3252 @@ async fn sync_rooms(
     let filter_definition = filter::FilterDefinition {
         event_fields: None,
         event_format: None,
-        account_data: Some(filter_all.clone()),
-        room: Some(room_filter.clone()),
-        presence: Some(filter_all.clone()),
+        account_data: Some(filter_all()),
+        room: Some(room_filter),
+        presence: Some(filter_all()),
     };
 
-    let response = client.request(
+    let response = pacing::LOCAL.request(|| client.request(
         r0::sync::sync_events::Request {
-            filter: Some(r0::sync::sync_events::Filter::FilterDefinition(filter_definition)),
+            filter: Some(r0::sync::sync_events::Filter::FilterDefinition(filter_definition.clone())),
             since: None,
             full_state: Some(true),
             set_presence: None,
             timeout: None,
         }
-    )
+    ))
     .await.expect("Could not get sync response");
-    eprintln!("next batch: {}", response.next_batch);
-    Ok(response.rooms)
+    Ok((response.rooms, response.next_batch))
 }
 
-pub async fn join_rooms(
+/// delivers all membership events since the given sync token
+async fn sync_membership_changes(
     client: HttpsClient,
-    room_aliases: Vec<RoomAliasId>,
-) -> Result<(usize, usize, usize), ruma_client::Error> {
-    eprintln!("Syncing…");
-    let rooms = sync_rooms(client.clone()).await.expect("error syncing");
-    eprintln!("Already joined rooms: {}", rooms.join.len());
-    // rooms that the bot was once a member of, but either left it (bot doesn't do that),
-    // was kicked or was banned. Rooms stay in here as long as I don't click on "remove" in Riot, it seems.
-    // => this is the difference between leave and forget endpoint, it seems.
-    // As invites do not check against this, this results in rejoin if kicked, but permission denied error if banned.
-    eprintln!("Left rooms (for whatever reason): {:?}", rooms.leave.keys());
+    since: String,
+) -> Result<r0::sync::sync_events::Response, ruma_client::Error> {
+    use r0::filter;
+    // Without a limit, synapse delivers 10 timeline events per room.
+    // Rooms with more membership changes have a limited timeline and are crawled completely.
+    let only_members = filter::RoomEventFilter {
+        limit: None,
+        types: Some(vec!["m.room.member".to_owned()]),
+        not_types: Vec::new(),
+        rooms: None,
+        not_rooms: Vec::new(),
+        senders: None,
+        not_senders: Vec::new(),
+    };
+    let room_filter = filter::RoomFilter {
+        include_leave: Some(true),
+        account_data: Some(filter_all_events()),
+        timeline: Some(only_members.clone()),
+        ephemeral: Some(filter_all_events()),
+        state: Some(only_members),
+        not_rooms: Vec::new(),
+        rooms: None,
+    };
+    let filter_definition = filter::FilterDefinition {
+        event_fields: None,
+        event_format: None,
+        account_data: Some(filter_all()),
+        room: Some(room_filter),
+        presence: Some(filter_all()),
+    };
 
-    // The simulation will assume the same message sending behaviour for all users.
-    // So skip twitter rooms as the users in there also mirror twitter followers,
-    // they don't actually take part in matrix but are for display purposes only - they never send messages.
-    // This is in contrast to e.g. bridged discord or IRC users, so they're left inside the graph.
-    let ignore_pattern = regex::Regex::new(r"^#twitter_#").unwrap();
+    pacing::LOCAL.request(|| client.request(
+        r0::sync::sync_events::Request {
+            filter: Some(r0::sync::sync_events::Filter::FilterDefinition(filter_definition.clone())),
+            since: Some(since.clone()),
+            full_state: Some(false),
+            set_presence: None,
+            timeout: None,
+        }
+    )).await
+}
 
-    let mut join_count: usize = 0;
-    let mut invite_count: usize = 0;
-    let rooms_to_join = room_aliases.len();
-    let invites_to_follow = rooms.invite.len();
+/// membership changes of a room from an incremental sync, in the order they happened
+fn membership_changes(
+    joined_room: &r0::sync::sync_events::JoinedRoom,
+) -> Vec<(String, Membership)> {
+    // the state section contains the changes that happened before the timeline section
+    let state_events = joined_room.state.events.iter().filter_map(|event| match event {
+        EventResult::Ok(event) => serde_json::to_value(event).ok(),
+        _ => None,
+    });
+    let timeline_events = joined_room.timeline.events.iter().filter_map(|event| match event {
+        EventResult::Ok(event) => serde_json::to_value(event).ok(),
+        _ => None,
+    });
+    state_events
+        .chain(timeline_events)
+        .filter(|event| event["type"] == "m.room.member")
+        .filter_map(|event| {
+            let state_key = event["state_key"].as_str()?.to_owned();
+            let membership = serde_json::from_value(event["content"]["membership"].clone()).ok()?;
+            Some((state_key, membership))
+        })
+        .collect()
+}
 
-    for (room_id, invite) in rooms.invite.clone().into_iter() {
-        Delay::new(ROOM_JOIN_DELAY).await.unwrap();
-        let mut canonical_alias = None;
-        for event in invite.clone().invite_state.events {
-            if let EventResult::Ok(StrippedState::RoomCanonicalAlias(canonical_alias_event)) = event {
-                canonical_alias = Some(canonical_alias_event.content.alias.clone().unwrap());
-                break;
-            }
-        }
+static KICKS_PATH: &str = "kicks.ron";
 
-        if let Some(canonical_alias) = canonical_alias {
-            if ignore_pattern.is_match(canonical_alias.alias()) {
-                eprintln!("ignoring {:?}", canonical_alias);
-                continue;
-            }
-            match client.request(
-                r0::membership::join_room_by_id_or_alias::Request {
-                    room_id_or_alias: RoomIdOrAliasId::RoomAliasId(canonical_alias.clone()),
-                    third_party_signed: None,
-                }
-            ).await {
-                Ok(_) => {
-                    invite_count += 1;
-                    eprintln!(
-                        "Followed invite to room: {:?} ({}/{})",
-                        canonical_alias.clone(),
-                        invite_count,
-                        invites_to_follow
-                    );
-                },
-                Err(e) => eprintln!("Error joining invited room {}: {:?}", canonical_alias, e),
-            };
-        } else {
-            // this seem to be mostly invites from NickServ bots or similar from IRC bridges
-            // -> one can directly follow invites by ID, as the inviting server is already known, it seems!
-            // TODO: directly join by id and skip canonical alias stuff from above?
-            eprintln!(
-                "could resolve canonical alias for invited room {:#?}, trying to join by room id",
-                invite
-            );
-            match client.request(
-                r0::membership::join_room_by_id_or_alias::Request {
-                    room_id_or_alias: RoomIdOrAliasId::RoomId(room_id.clone()),
-                    third_party_signed: None,
-                }
-            ).await {
-                Ok(_) => {
-                    invite_count += 1;
-                    eprintln!(
-                        "Followed invite to room: {:?} ({}/{})",
-                        room_id.clone(),
-                        invite_count,
-                        invites_to_follow
-                    );
-                },
-                Err(e) => eprintln!("Error joining invited room through id{:?}: {:?}", invite, e),
-            };
-        }
+/// A room the bot was kicked or banned from.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Kick {
+    pub room_id: RoomId,
+    /// when the kick or ban was noticed
+    pub recorded_at: time::SystemTime,
+    pub banned: bool,
+}
+
+pub fn load_kicks() -> Vec<Kick> {
+    match fs::File::open(KICKS_PATH) {
+        Ok(file) => {
+            let reader = io::BufReader::new(file);
+            ron::de::from_reader(reader).expect("Could not deserialize kicks.ron")
+        },
+        Err(_) => Vec::new(),
     }
+}
 
-    if room_aliases.is_empty() {
-        eprintln!("no new rooms given to join.");
-        return Ok((join_count, invite_count, rooms.leave.len()));
+fn store_kicks(kicks: &[Kick]) -> Result<(), io::Error> {
+    let file = fs::File::create(KICKS_PATH)?;
+    let mut buffer = io::BufWriter::new(file);
+    write!(
+        &mut buffer,
+        "{}",
+        ron::ser::to_string_pretty(&kicks, ron::ser::PrettyConfig::default()).unwrap()
+    )
+}
+
+/// removes a kick from the record, as an invite after a kick means the room wants the bot back
+fn forgive_kick(room_id: &RoomId) {
+    let mut kicks = load_kicks();
+    let kick_count = kicks.len();
+    kicks.retain(|kick| &kick.room_id != room_id);
+    if kicks.len() != kick_count {
+        store_kicks(&kicks).expect("Could not store kicks.ron");
     }
+}
 
-    let joined_rooms_set: HashSet<RoomId> = HashSet::from_iter(rooms.join.keys().cloned());
-    let left_rooms_set: HashSet<RoomId> = HashSet::from_iter(rooms.leave.keys().cloned());
-    let invited_rooms_set: HashSet<RoomId> = HashSet::from_iter(rooms.invite.keys().cloned());
+/// Records the left rooms the bot was kicked or banned from, and removes all recorded rooms from the graphs
+/// stored in data/graphs. Returns all recorded kicks.
+/// A dry run only classifies the left rooms, without storing the kicks or touching the stored graphs.
+async fn honor_kicks(
+    client: HttpsClient,
+    left_rooms: Vec<RoomId>,
+    dry_run: bool,
+) -> Result<Vec<Kick>, ruma_client::Error> {
+    let mut kicks = load_kicks();
+    let recorded: HashSet<RoomId> = kicks.iter().map(|kick| kick.room_id.clone()).collect();
+    let own_user_id = client.session().expect("not logged in").user_id;
 
-    for alias in room_aliases {
-        if ignore_pattern.is_match(alias.alias()) {
-            eprintln!("ignoring {:?}", alias);
+    // The bot only leaves rooms through exit, which also forgets them,
+    // so every room that is still known as left is one the bot was kicked or banned from.
+    for room_id in left_rooms {
+        if recorded.contains(&room_id) {
             continue;
         }
+        let own_membership = room_state_content(
+            client.clone(),
+            room_id.clone(),
+            EventType::RoomMember,
+            own_user_id.to_string(),
+        )
+        .await
+        .unwrap_or_else(|e| {
+            eprintln!("Could not read own membership in room {}, taking it as kick: {:?}", room_id, e);
+            None
+        });
+        let banned = state_field::<String>(&own_membership, "membership")
+            .map_or(false, |membership| membership == "ban");
+        eprintln!("Was {} from room {}", if banned { "banned" } else { "kicked" }, room_id);
+        kicks.push(Kick {
+            room_id,
+            recorded_at: time::SystemTime::now(),
+            banned,
+        });
+    }
+    if dry_run {
+        return Ok(kicks);
+    }
+    if kicks.len() != recorded.len() {
+        store_kicks(&kicks).expect("Could not store kicks.ron");
+    }
+
+    purge_stored_graphs(&kicks);
+    Ok(kicks)
+}
 
-        let room_id = match resolve_alias(client.clone(), alias.clone()).await {
-            Ok(room_id) => room_id,
+/// removes the given rooms from all stored graphs which have room fingerprints in data/fingerprints
+fn purge_stored_graphs(kicks: &[Kick]) {
+    if kicks.is_empty() {
+        return;
+    }
+    for dir in matrixgraph::stored_graph_dirs().expect("Could not list stored graphs") {
+        let mut fingerprints = match matrixgraph::read_room_fingerprints(&dir) {
+            Ok(fingerprints) => fingerprints,
             Err(e) => {
-                eprintln!("Could not resolve room {}: {:?}", alias, e);
+                eprintln!("Cannot purge {}: no room fingerprints ({})", dir.display(), e);
                 continue;
             },
         };
-        // if the bot is not yet in that room, and was not invited (which was already handled), and
-        // has not left that room, i.e. was kicked from that room, try to join.
-        if !joined_rooms_set.contains(&room_id)
-            && !invited_rooms_set.contains(&room_id)
-            && !left_rooms_set.contains(&room_id)
-        {
-            match client.request(
-                r0::membership::join_room_by_id_or_alias::Request {
-                    room_id_or_alias: RoomIdOrAliasId::RoomAliasId(alias.clone()),
-                    third_party_signed: None,
-                }
-            ).await {
-                Ok(_) => {
-                    join_count += 1;
-                    eprintln!(
-                        "Joined room: {:?} ({}/{})",
-                        alias, join_count, rooms_to_join
-                    );
-                },
-                Err(e) => eprintln!("Error joining room {}: {:?}", room_id, e),
-            };
+        let room_ids: HashSet<u64> = kicks
+            .iter()
+            .filter_map(|kick| fingerprints.remove(&kick.room_id))
+            .collect();
+        if room_ids.is_empty() {
+            continue;
+        }
 
-            Delay::new(ROOM_JOIN_DELAY).await.expect("wait failed");
-        } else {
-            eprintln!(
-                "already joined, invited or was kicked from room {}.",
-                room_id
-            );
+        let mut graph = matrixgraph::read_graph(dir.join("graph.json")).unwrap();
+        let removed_rooms = matrixgraph::remove_rooms(&mut graph, &room_ids);
+        matrixgraph::write_graph(&graph, &dir).unwrap();
+        matrixgraph::export_graph_to_dot(&graph, &dir).unwrap();
+        matrixgraph::export_graph_to_graphml(&graph, &dir).unwrap();
+        matrixgraph::write_room_fingerprints(&fingerprints, &dir).unwrap();
+        // the delta would still list the removed rooms, together with their users and servers
+        let delta_path = dir.join("delta.json");
+        if delta_path.exists() {
+            fs::remove_file(delta_path).expect("Could not remove delta of purged graph");
         }
+        eprintln!("Removed {} rooms from {}", removed_rooms, dir.display());
     }
-    Ok((join_count, invite_count, rooms.leave.len()))
 }
 
-async fn leave_and_forget_room(
-    client: HttpsClient,
-    room_id: RoomId,
-) -> Result<(), ruma_client::Error> {
-    client.request(
-        r0::membership::leave_room::Request {
-            room_id: room_id.clone(),
+static JOIN_QUEUE_PATH: &str = "join_queue.ron";
+// make_join and send_join are the expensive part of a join for the remote server
+static SERVER_JOIN_INTERVAL: time::Duration = time::Duration::from_secs(120);
+// a room that failed this often is not tried again when resuming the join queue
+const MAX_JOIN_ATTEMPTS: usize = 3;
+
+static INVENTORY_PATH: &str = "inventory.ron";
+
+/// All aliases the bot came across, by the room they point to.
+/// The inventory stays local and is never published, as aliases of rooms that aren't listed in
+/// any room directory are not public.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Inventory {
+    rooms: HashMap<RoomId, Vec<RoomAliasId>>,
+}
+
+impl Inventory {
+    /// records that the alias points to the room, forgetting where it pointed before
+    fn add(&mut self, room_id: RoomId, alias: RoomAliasId) {
+        for aliases in self.rooms.values_mut() {
+            aliases.retain(|known| known != &alias);
         }
-    ).await?;
+        self.rooms.retain(|_, aliases| !aliases.is_empty());
+        self.rooms.entry(room_id).or_default().push(alias);
+    }
 
-    client.request(
-        r0::membership::forget_room::Request { room_id }
-    ).await?;
-    Ok(())
+    pub fn aliases(&self, room_id: &RoomId) -> &[RoomAliasId] {
+        self.rooms.get(room_id).map_or(&[][..], Vec::as_slice)
+    }
+
+    pub fn room_id(&self, alias: &RoomAliasId) -> Option<&RoomId> {
+        self.rooms
+            .iter()
+            .find(|(_, aliases)| aliases.contains(alias))
+            .map(|(room_id, _)| room_id)
+    }
 }
 
-pub async fn resolve_alias(
-    client: HttpsClient,
-    room_alias: RoomAliasId,
-) -> Result<RoomId, ruma_client::Error> {
-    let response = client.request(
-        r0::alias::get_alias::Request { room_alias }
-    ).await?;
-    Ok(response.room_id)
+pub fn load_inventory() -> Inventory {
+    match fs::File::open(INVENTORY_PATH) {
+        Ok(file) => {
+            let reader = io::BufReader::new(file);
+            ron::de::from_reader(reader).expect("Could not deserialize inventory.ron")
+        },
+        Err(_) => Inventory::default(),
+    }
 }
 
-pub async fn into_room_id(
+fn store_inventory(inventory: &Inventory) -> Result<(), io::Error> {
+    let file = fs::File::create(INVENTORY_PATH)?;
+    let mut buffer = io::BufWriter::new(file);
+    write!(
+        &mut buffer,
+        "{}",
+        ron::ser::to_string_pretty(&inventory, ron::ser::PrettyConfig::default()).unwrap()
+    )
+}
+
+/// The room id of the room, asking the homeserver first, as aliases can be moved to other rooms.
+/// The inventory is only used for aliases that no longer resolve.
+pub async fn lookup_room_id(
     client: HttpsClient,
-    room_id_or_alias_id: RoomIdOrAliasId,
+    room: RoomIdOrAliasId,
 ) -> Result<RoomId, ruma_client::Error> {
-    match room_id_or_alias_id {
-        RoomIdOrAliasId::RoomId(room_id) => Ok(room_id),
-        RoomIdOrAliasId::RoomAliasId(alias) => resolve_alias(client.clone(), alias).await,
+    let alias = match room {
+        RoomIdOrAliasId::RoomId(room_id) => return Ok(room_id),
+        RoomIdOrAliasId::RoomAliasId(alias) => alias,
+    };
+    match resolve_alias(client, alias.clone()).await {
+        Err(e) if is_not_found(&e) => match load_inventory().room_id(&alias) {
+            Some(room_id) => {
+                eprintln!("{} no longer resolves, it pointed to {} before.", alias, room_id);
+                Ok(room_id.clone())
+            },
+            None => Err(e),
+        },
+        result => result,
     }
 }
 
-/// delivers the user ids of all users currently joined in the given room
-async fn room_members(
-    client: HttpsClient,
-    room_id: RoomId,
-) -> Result<Vec<String>, ruma_client::Error> {
-    let response = client.request(
-        r0::sync::get_member_events::Request {
-            room_id: room_id.clone(),
-        }
-    ).await?;
+/// Where a room of the join queue is on its way from an alias to a joined room.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum JoinState {
+    Pending,
+    /// the alias was resolved to a room id, but the room was not joined yet
+    Resolved,
+    Joined,
+    /// resolving or joining failed, see the last error
+    Failed,
+    Kicked,
+    Banned,
+    /// matched the ignore rules
+    Ignored,
+    /// another alias of the same room is joined instead
+    Duplicate,
+}
 
-    // in the case of join membership events it's probably always the case that sender is the same user
-    // the event relates to, but actually, the state key is the field building the relationship to the user.
-    let state_keys = response
-        .chunk
-        .into_iter()
-        .filter_map(|event_result| event_result.into_result().ok())
-        .filter(|event| event.content.membership == MembershipState::Join)
-        .map(|event| event.state_key)
-        .collect();
-    Ok(state_keys)
+/// A room to join, optionally with servers that are in the room and can be asked to join it.
+/// Rooms given by id can only be joined over federation if a server in the room is known.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JoinTarget {
+    pub room: RoomIdOrAliasId,
+    pub via: Vec<String>,
 }
 
-fn hash(builder: &dyn BuildHasher<Hasher = DefaultHasher>, x: &impl Hash) -> u64 {
-    let mut hasher = builder.build_hasher();
-    x.hash(&mut hasher);
-    hasher.finish()
+impl JoinTarget {
+    pub fn new(room: RoomIdOrAliasId) -> Self {
+        JoinTarget { room, via: Vec::new() }
+    }
 }
 
-pub async fn crawl(
-    client: HttpsClient,
-) -> Result<(usize, usize, usize), ruma_client::Error> {
-    // * ignore ourself and voyager, as we are in all rooms but silent, so we won't send messages in the simulation
-    // * weho.st and disroot.org requested to opt out as whole server, this will lead to an
-    //   anonymized graph in which those servers and the users on them never existed.
-    let member_ignore_pattern = regex::Regex::new(
-        r"^(@.*:dsn-traveller.dsn.scc.kit.edu|@voyager:t2bot.io|@.*:weho.st|@.*:disroot.org)$",
-    )
-    .unwrap();
+/// A room of the join queue.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct QueuedRoom {
+    // queues from before rooms could be given by id only contain aliases
+    #[serde(alias = "alias")]
+    pub room: RoomIdOrAliasId,
+    /// servers to join the room through
+    #[serde(default)]
+    pub via: Vec<String>,
+    pub room_id: Option<RoomId>,
+    pub state: JoinState,
+    /// failed attempts to resolve or join the room
+    pub retries: usize,
+    pub last_error: Option<String>,
+}
 
-    let joined_rooms = joined_rooms(client.clone()).await?;
-    let mut graph: Graph<Node, (), petgraph::Undirected> = Graph::new_undirected();
+impl QueuedRoom {
+    fn new(target: JoinTarget) -> Self {
+        let room_id = match &target.room {
+            RoomIdOrAliasId::RoomId(room_id) => Some(room_id.clone()),
+            RoomIdOrAliasId::RoomAliasId(_) => None,
+        };
+        QueuedRoom {
+            state: if room_id.is_some() { JoinState::Resolved } else { JoinState::Pending },
+            room: target.room,
+            via: target.via,
+            room_id,
+            retries: 0,
+            last_error: None,
+        }
+    }
 
-    let mut room_indexes = HashMap::<RoomId, NodeIndex>::new();
-    let mut user_indexes = HashMap::<UserId, NodeIndex>::new();
-    let mut server_indexes = HashMap::<ServerId, NodeIndex>::new();
+    /// whether joining the room still has to be tried
+    fn is_unfinished(&self) -> bool {
+        match self.state {
+            JoinState::Pending | JoinState::Resolved => true,
+            JoinState::Failed => self.retries < MAX_JOIN_ATTEMPTS,
+            _ => false,
+        }
+    }
 
-    // pseudonymization:
-    // on each crawl, choose a different random has function
-    let hash_key = RandomState::new();
-    let mut crawled_rooms = 0;
-    let rooms_to_crawl = joined_rooms.len();
+    fn fail(&mut self, error: &ruma_client::Error) {
+        self.state = JoinState::Failed;
+        self.retries += 1;
+        self.last_error = Some(format!("{:?}", error));
+    }
+}
 
-    for room in joined_rooms {
-        Delay::new(ROOM_CRAWL_DELAY).await.expect("wait failed");
+fn load_join_queue() -> Vec<QueuedRoom> {
+    match fs::File::open(JOIN_QUEUE_PATH) {
+        Ok(file) => {
+            let reader = io::BufReader::new(file);
+            ron::de::from_reader(reader).expect("Could not deserialize join_queue.ron")
+        },
+        Err(_) => Vec::new(),
+    }
+}
 
-        // occasionally this resulted in a bad gateway error
-        // could not find the synapse log lines for that, but it's probably due to server overload.
-        // redoing it once worked fine.
-        let members = match room_members(client.clone(), room.clone()).await {
-            Ok(members) => members,
-            Err(e) => {
-                eprintln!("error getting room members: {:?}, retrying once.", e);
-                room_members(client.clone(), room.clone()).await?
-            },
-        };
+fn store_join_queue(queue: &[QueuedRoom]) -> Result<(), io::Error> {
+    let file = fs::File::create(JOIN_QUEUE_PATH)?;
+    let mut buffer = io::BufWriter::new(file);
+    write!(
+        &mut buffer,
+        "{}",
+        ron::ser::to_string_pretty(&queue, ron::ser::PrettyConfig::default()).unwrap()
+    )
+}
 
-        for member in members {
-            if member_ignore_pattern.is_match(member.as_str()) {
-                continue;
-            }
-            // if we came as far as here, there's at least one non-ignored user in that room, and
-            // we can add it to the graph.
-            let room_idx = room_indexes.entry(room.clone()).or_insert_with(|| {
-                graph.add_node(Node {
-                    kind: NodeType::Room,
-                    id: hash(&hash_key, &room),
-                })
-            });
+/// indices of the unfinished rooms of the queue, interleaved by the server of their alias or id
+fn join_order(queue: &[QueuedRoom]) -> Vec<usize> {
+    let mut servers: Vec<String> = Vec::new();
+    let mut rooms_by_server: HashMap<String, VecDeque<usize>> = HashMap::new();
+    for (idx, room) in queue.iter().enumerate() {
+        if !room.is_unfinished() {
+            continue;
+        }
+        let server = server_name(&room.room);
+        if !rooms_by_server.contains_key(&server) {
+            servers.push(server.clone());
+        }
+        rooms_by_server.entry(server).or_default().push_back(idx);
+    }
 
-            let user_id = UserId::try_from(member.as_str()).unwrap();
-            let server_id = ServerId::new(&user_id);
-            let is_new_server = !server_indexes.contains_key(&server_id);
-            let server_idx = server_indexes.entry(server_id.clone()).or_insert_with(|| {
-                graph.add_node(Node {
-                    kind: NodeType::Server,
-                    id: hash(&hash_key, &server_id),
-                })
-            });
+    let room_count = rooms_by_server.values().map(VecDeque::len).sum();
+    let mut order = Vec::with_capacity(room_count);
+    while order.len() < room_count {
+        for server in &servers {
+            if let Some(idx) = rooms_by_server.get_mut(server).unwrap().pop_front() {
+                order.push(idx);
+            }
+        }
+    }
+    order
+}
 
-            // is_new_server -> !user_indexes.contains_key,
-            // if this is a new server, it can't have users yet
-            debug_assert!(
-                !is_new_server || !user_indexes.contains_key(&user_id),
-                "Server {} is new, but we already found User {}!",
-                server_id,
-                user_id
-            );
-            let user_idx = user_indexes.entry(user_id.clone()).or_insert_with(|| {
-                let user_idx = graph.add_node(Node {
-                    kind: NodeType::User,
-                    id: hash(&hash_key, &user_id),
-                });
-                graph.add_edge(user_idx, *server_idx, ());
-                user_idx
-            });
+pub struct JoinOptions {
+    /// add the rooms to the existing join queue and try its unfinished rooms again instead of replacing it
+    pub resume: bool,
+    /// minimum time between two joins of rooms with aliases or ids of the same server
+    pub server_interval: time::Duration,
+    /// rooms that are not joined
+    pub ignore_rules: IgnoreRules,
+    /// which invites are followed, the others are rejected
+    pub invite_rules: InviteRules,
+    /// only print the intended joins and invite decisions to stdout, without changing any membership
+    pub dry_run: bool,
+    /// when no more aliases are resolved and no more rooms are joined, the rest stays in the join queue
+    pub deadline: Option<time::Instant>,
+}
 
-            graph.add_edge(*user_idx, *room_idx, ());
-            // connect room and the user's server in case that edge was not yet there
-            graph.update_edge(*server_idx, *room_idx, ());
+impl Default for JoinOptions {
+    fn default() -> Self {
+        JoinOptions {
+            resume: false,
+            server_interval: SERVER_JOIN_INTERVAL,
+            ignore_rules: IgnoreRules::default(),
+            invite_rules: InviteRules::default(),
+            dry_run: false,
+            deadline: None,
         }
-        crawled_rooms += 1;
-        eprintln!("Crawled {}/{} rooms", crawled_rooms, rooms_to_crawl);
     }
+}
 
-    assert!(matrixgraph::is_wellformed_graph(&graph));
+/// How joining a room ended in a join run.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JoinOutcome {
+    Joined,
+    AlreadyMember,
+    /// an invite to the room was accepted instead
+    Invited,
+    PreviouslyKicked,
+    PreviouslyBanned,
+    /// matched the ignore rules
+    Ignored,
+    /// rejected by the invite rules
+    InviteRejected,
+    /// a dry run would have joined the room
+    WouldJoin,
+    /// another alias of the same room was joined instead
+    Duplicate,
+    AliasNotFound,
+    FederationTimeout,
+    Forbidden,
+    RateLimited,
+    /// any other error
+    Failed,
+}
 
-    let graph = matrixgraph::anonymize_graph(graph);
+/// whether the server answered that the requested alias, event or room doesn't exist
+fn is_not_found(error: &ruma_client::Error) -> bool {
+    // ruma-api only keeps the status code of failed responses, later versions keep the errcode
+    let error = format!("{:?}", error);
+    error.contains("M_NOT_FOUND") || error.contains("StatusCode(404)")
+}
 
-    let dir = matrixgraph::graph_dir();
-    matrixgraph::write_graph(&graph, &dir).unwrap();
-    matrixgraph::export_graph_to_dot(&graph, &dir).unwrap();
-    matrixgraph::export_graph_to_graphml(&graph, &dir).unwrap();
+impl JoinOutcome {
+    fn of_error(error: &ruma_client::Error) -> Self {
+        if pacing::is_rate_limited(error) {
+            return JoinOutcome::RateLimited;
+        }
+        if is_not_found(error) {
+            return JoinOutcome::AliasNotFound;
+        }
+        let error = format!("{:?}", error);
+        if error.contains("M_FORBIDDEN") {
+            JoinOutcome::Forbidden
+        } else if error.contains("Gateway Timeout") || error.to_lowercase().contains("timed out") {
+            JoinOutcome::FederationTimeout
+        } else {
+            JoinOutcome::Failed
+        }
+    }
 
-    Ok((room_indexes.len(), user_indexes.len(), server_indexes.len()))
+    fn as_str(self) -> &'static str {
+        match self {
+            JoinOutcome::Joined => "joined",
+            JoinOutcome::AlreadyMember => "already_member",
+            JoinOutcome::Invited => "invited",
+            JoinOutcome::PreviouslyKicked => "previously_kicked",
+            JoinOutcome::PreviouslyBanned => "previously_banned",
+            JoinOutcome::Ignored => "ignored",
+            JoinOutcome::InviteRejected => "invite_rejected",
+            JoinOutcome::WouldJoin => "would_join",
+            JoinOutcome::Duplicate => "duplicate",
+            JoinOutcome::AliasNotFound => "alias_not_found",
+            JoinOutcome::FederationTimeout => "federation_timeout",
+            JoinOutcome::Forbidden => "forbidden",
+            JoinOutcome::RateLimited => "rate_limited",
+            JoinOutcome::Failed => "failed",
+        }
+    }
 }
 
-pub async fn exit_all(
-    client: HttpsClient,
-    control_room: RoomId,
-) -> Result<(usize, usize), ruma_client::Error> {
-    let joined_rooms = joined_rooms(client.clone()).await?;
+/// The outcome of one room of a join run, for analyzing the health of the federation.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JoinReport {
+    /// the alias or id the room was given by
+    pub room: RoomIdOrAliasId,
+    pub room_id: Option<RoomId>,
+    /// whether the room was joined by following an invite
+    pub invite: bool,
+    pub outcome: JoinOutcome,
+    /// time spent resolving and joining the room, including waits for rate limits,
+    /// but not the waits between joins of the same server
+    pub elapsed_ms: u64,
+    pub error: Option<String>,
+}
 
-    // ignore control room
-    let joined_count = joined_rooms.len() - 1;
-    let mut left_count = 0;
+impl JoinReport {
+    fn new(
+        room: RoomIdOrAliasId,
+        room_id: Option<RoomId>,
+        outcome: JoinOutcome,
+        elapsed: time::Duration,
+    ) -> Self {
+        JoinReport {
+            room,
+            room_id,
+            invite: false,
+            outcome,
+            elapsed_ms: elapsed.as_millis() as u64,
+            error: None,
+        }
+    }
 
-    // leaving as well as forgetting so that the server could part the federation for that rooms.
-    // Also, if we would not forget leaved rooms, they would appear as rooms where the bot has been
-    // kicked from on a later join run.
-    // TODO: is leave_and_forget_room enough so that the server can be shut down
-    // without being a dead member of the federation?
-    for room_id in joined_rooms {
-        if room_id != control_room {
-            Delay::new(ROOM_CRAWL_DELAY).await.expect("wait failed");
-            match leave_and_forget_room(client.clone(), room_id.clone()).await {
-                Ok(_) => {
-                    left_count += 1;
-                    eprintln!("Left room: {} ({}/{})", room_id, left_count, joined_count);
-                },
-                Err(e) => eprintln!("Error leaving / forgetting room {}: {:?}", room_id, e),
-            }
+    fn failed(
+        room: RoomIdOrAliasId,
+        room_id: Option<RoomId>,
+        error: &ruma_client::Error,
+        elapsed: time::Duration,
+    ) -> Self {
+        JoinReport {
+            error: Some(format!("{:?}", error)),
+            ..JoinReport::new(room, room_id, JoinOutcome::of_error(error), elapsed)
         }
     }
+}
 
-    Ok((left_count, joined_count))
+pub struct JoinSummary {
+    /// rooms joined from the join queue
+    pub join_count: usize,
+    /// invites followed
+    pub invite_count: usize,
+    /// rooms the bot is not a member of anymore
+    pub leave_count: usize,
+    pub reports: Vec<JoinReport>,
+}
+
+/// Writes the join reports as JSON and as CSV to data/joins, named by the time of writing.
+pub fn write_join_reports(reports: &[JoinReport]) -> Result<(), io::Error> {
+    let dir = Path::new("data/joins");
+    fs::create_dir_all(dir)?;
+    let name = format!("joins_{}", chrono::Local::now().format("%Y-%m-%dT%H-%M-%S"));
+
+    let file = fs::File::create(dir.join(format!("{}.json", name)))?;
+    let mut writer = io::BufWriter::new(file);
+    serde_json::to_writer_pretty(&mut writer, reports).expect("Could not serialize join reports");
+    writer.flush()?;
+
+    let file = fs::File::create(dir.join(format!("{}.csv", name)))?;
+    let mut writer = io::BufWriter::new(file);
+    writeln!(writer, "room,room_id,invite,outcome,elapsed_ms,error")?;
+    for report in reports {
+        writeln!(
+            writer,
+            "{},{},{},{},{},{}",
+            csv_field(&report.room.to_string()),
+            report.room_id.as_ref().map_or(String::new(), |room_id| room_id.to_string()),
+            report.invite,
+            report.outcome.as_str(),
+            report.elapsed_ms,
+            csv_field(report.error.as_ref().map_or("", String::as_str)),
+        )?;
+    }
+    writer.flush()?;
+    eprintln!("Wrote join reports to {}", dir.join(&name).display());
+    Ok(())
 }
 
-pub async fn exit(
+/// quotes a CSV field if needed, room ids never need quoting, but given aliases might
+fn csv_field(field: &str) -> String {
+    if field.contains(|c: char| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Follows the invites accepted by the invite rules and rejects the others,
+/// then joins the given rooms through the join queue in join_queue.ron, which records the progress of every room.
+/// All aliases are resolved before joining, and each room is joined once, however many of its aliases are given.
+/// Rooms are joined in turns by the server of their alias or id, so that no server gets many joins in a row.
+/// A dry run leaves the invites and the join queue untouched and counts the rooms it would join.
+/// At the deadline, the joins stop and the summary covers the rooms that were tried until then.
+/// Reports the outcome for every invite and every room of the queue that was tried.
+pub async fn join_rooms(
     client: HttpsClient,
-    room_id: RoomId,
-) -> Result<(), ruma_client::Error> {
-    // leaving as well as forgetting so that the server could part the federation for that rooms.
-    // Also, if we would not forget leaved rooms, they would appear as rooms where the bot has been
+    rooms_to_queue: Vec<JoinTarget>,
+    options: JoinOptions,
+) -> Result<JoinSummary, ruma_client::Error> {
+    eprintln!("Syncing…");
+    let (rooms, _) = sync_rooms(client.clone()).await.expect("error syncing");
+    eprintln!("Already joined rooms: {}", rooms.join.len());
+    // rooms that the bot was once a member of, but either left it (bot doesn't do that),
+    // was kicked or was banned. Rooms stay in here as long as I don't click on "remove" in Riot, it seems.
+    // => this is the difference between leave and forget endpoint, it seems.
+    // As invites do not check against this, this results in rejoin if kicked, but permission denied error if banned.
+    let kicks =
+        honor_kicks(client.clone(), rooms.leave.keys().cloned().collect(), options.dry_run).await?;
+    eprintln!("Kicked from or banned in rooms: {}", kicks.len());
+
+    let ignore = IgnoreFilter::new(&options.ignore_rules).expect("invalid ignore pattern");
+
+    let mut join_count: usize = 0;
+    let mut invite_count: usize = 0;
+    let invites_to_follow = rooms.invite.len();
+
+    let invite_policy = InvitePolicy::new(&options.invite_rules).expect("invalid invite pattern");
+    let own_user_id = client.session().expect("not logged in").user_id;
+    let mut accepted_invites: HashSet<RoomId> = HashSet::new();
+    let mut reports = Vec::new();
+    let mut inventory = load_inventory();
+    let directory_members: HashMap<RoomId, u64> = load_discovery()
+        .into_iter()
+        .filter_map(|room| Some((room.room_id, room.joined_members?)))
+        .collect();
+
+    for (room_id, invite) in rooms.invite.clone().into_iter() {
+        let started = time::Instant::now();
+        let mut canonical_alias = None;
+        let mut inviter = None;
+        for event in invite.invite_state.events {
+            match event {
+                EventResult::Ok(StrippedState::RoomCanonicalAlias(canonical_alias_event)) => {
+                    canonical_alias = canonical_alias_event.content.alias;
+                },
+                EventResult::Ok(StrippedState::RoomMember(member_event)) => {
+                    if member_event.state_key == own_user_id.to_string() {
+                        inviter = Some(member_event.sender);
+                    }
+                },
+                _ => {},
+            }
+        }
+        if let Some(alias) = &canonical_alias {
+            inventory.add(room_id.clone(), alias.clone());
+        }
+        let inviter_server = inviter.as_ref().map(|inviter| server_name(inviter));
+
+        let rejection = if ignore.ignores_room(&room_id) {
+            Some("room is ignored".to_string())
+        } else if canonical_alias.as_ref().map_or(false, |alias| ignore.ignores_alias(alias)) {
+            Some("alias is ignored".to_string())
+        } else {
+            invite_policy.rejection(inviter.as_ref(), directory_members.get(&room_id).cloned()).err()
+        };
+        if let Some(reason) = rejection {
+            reports.push(JoinReport {
+                invite: true,
+                ..JoinReport::new(
+                    RoomIdOrAliasId::RoomId(room_id.clone()),
+                    Some(room_id.clone()),
+                    JoinOutcome::InviteRejected,
+                    started.elapsed(),
+                )
+            });
+            if options.dry_run {
+                println!("reject invite {} ({})", room_id, reason);
+                continue;
+            }
+            eprintln!(
+                "Rejecting invite to room {} from {:?} (server {:?}): {}",
+                room_id, inviter, inviter_server, reason
+            );
+            // forgetting the room keeps the rejection from being taken for a kick
+            if let Err(e) = leave_and_forget_room(client.clone(), room_id.clone()).await {
+                eprintln!("Error rejecting invite to room {}: {:?}", room_id, e);
+            }
+            continue;
+        }
+        eprintln!(
+            "Accepting invite to room {} from {:?} (server {:?})",
+            room_id, inviter, inviter_server
+        );
+        accepted_invites.insert(room_id.clone());
+        if options.dry_run {
+            println!("accept invite {}", room_id);
+            invite_count += 1;
+            reports.push(JoinReport {
+                invite: true,
+                ..JoinReport::new(
+                    RoomIdOrAliasId::RoomId(room_id.clone()),
+                    Some(room_id.clone()),
+                    JoinOutcome::WouldJoin,
+                    started.elapsed(),
+                )
+            });
+            continue;
+        }
+
+        let (room, via): (RoomIdOrAliasId, Vec<String>) = match canonical_alias {
+            Some(alias) => (RoomIdOrAliasId::RoomAliasId(alias), Vec::new()),
+            // this seem to be mostly invites from NickServ bots or similar from IRC bridges
+            // -> one can directly follow invites by ID, as the inviting server is in the room
+            None => (
+                RoomIdOrAliasId::RoomId(room_id.clone()),
+                inviter_server.into_iter().collect(),
+            ),
+        };
+        let report = match join_room(client.clone(), room.clone(), &via).await {
+            Ok(_) => {
+                invite_count += 1;
+                forgive_kick(&room_id);
+                eprintln!(
+                    "Followed invite to room: {} ({}/{})",
+                    room, invite_count, invites_to_follow
+                );
+                JoinReport::new(room, Some(room_id), JoinOutcome::Joined, started.elapsed())
+            },
+            Err(e) => {
+                eprintln!("Error joining invited room {}: {:?}", room, e);
+                JoinReport::failed(room, Some(room_id), &e, started.elapsed())
+            },
+        };
+        reports.push(JoinReport { invite: true, ..report });
+    }
+
+    let dry_run = options.dry_run;
+    let store_queue = |queue: &[QueuedRoom]| {
+        if !dry_run {
+            store_join_queue(queue).expect("Could not store join queue");
+        }
+    };
+    let mut queue = if options.resume {
+        load_join_queue()
+    } else {
+        let queue = load_join_queue();
+        let unfinished = queue.iter().filter(|room| room.is_unfinished()).count();
+        if unfinished > 0 && !dry_run {
+            eprintln!("Replacing the join queue, dropping {} unfinished rooms.", unfinished);
+        }
+        Vec::new()
+    };
+    let queued_rooms: HashSet<RoomIdOrAliasId> =
+        HashSet::from_iter(queue.iter().map(|room| room.room.clone()));
+    queue.extend(
+        rooms_to_queue
+            .into_iter()
+            .filter(|target| !queued_rooms.contains(&target.room))
+            .map(QueuedRoom::new),
+    );
+    store_queue(&queue);
+
+    if !queue.iter().any(QueuedRoom::is_unfinished) {
+        if !dry_run {
+            store_inventory(&inventory).expect("Could not store inventory");
+        }
+        eprintln!("no new rooms given to join.");
+        return Ok(JoinSummary {
+            join_count,
+            invite_count,
+            leave_count: rooms.leave.len(),
+            reports,
+        });
+    }
+
+    let joined_rooms_set: HashSet<RoomId> = HashSet::from_iter(rooms.join.keys().cloned());
+    let kicks: HashMap<RoomId, Kick> =
+        HashMap::from_iter(kicks.into_iter().map(|kick| (kick.room_id.clone(), kick)));
+
+    // Resolve all aliases up front, so that several aliases of the same room only cost one join.
+    // Lookups are paced like directory requests, much faster than joins.
+    let mut resolve_times: HashMap<usize, time::Duration> = HashMap::new();
+    for idx in join_order(&queue) {
+        if is_past(options.deadline) {
+            break;
+        }
+        let room = &mut queue[idx];
+        let alias = match &room.room {
+            RoomIdOrAliasId::RoomAliasId(alias) => alias.clone(),
+            RoomIdOrAliasId::RoomId(_) => continue,
+        };
+        let started = time::Instant::now();
+        if ignore.ignores_alias(&alias) {
+            eprintln!("ignoring {:?}", alias);
+            room.state = JoinState::Ignored;
+            reports.push(JoinReport::new(
+                room.room.clone(),
+                None,
+                JoinOutcome::Ignored,
+                started.elapsed(),
+            ));
+            store_queue(&queue);
+            continue;
+        }
+        // resolved in an earlier run
+        if room.room_id.is_some() {
+            continue;
+        }
+        match resolve_alias(client.clone(), alias.clone()).await {
+            Ok(room_id) => {
+                inventory.add(room_id.clone(), alias);
+                room.room_id = Some(room_id);
+                room.state = JoinState::Resolved;
+                resolve_times.insert(idx, started.elapsed());
+            },
+            Err(e) => {
+                eprintln!("Could not resolve room {}: {:?}", room.room, e);
+                room.fail(&e);
+                reports.push(JoinReport::failed(room.room.clone(), None, &e, started.elapsed()));
+            },
+        }
+        store_queue(&queue);
+    }
+    if !dry_run {
+        store_inventory(&inventory).expect("Could not store inventory");
+    }
+
+    // of several aliases of the same room, only the first one in join order is joined
+    let mut queued_room_ids: HashMap<RoomId, usize> = HashMap::new();
+    let mut order = Vec::new();
+    for idx in join_order(&queue) {
+        // rooms whose alias couldn't be resolved don't have an id
+        let room_id = match queue[idx].room_id.clone() {
+            Some(room_id) => room_id,
+            None => continue,
+        };
+        if let Some(&first) = queued_room_ids.get(&room_id) {
+            eprintln!("{} is another alias of {}.", queue[idx].room, queue[first].room);
+            queue[idx].state = JoinState::Duplicate;
+            reports.push(JoinReport::new(
+                queue[idx].room.clone(),
+                Some(room_id),
+                JoinOutcome::Duplicate,
+                resolve_times.get(&idx).cloned().unwrap_or_default(),
+            ));
+        } else {
+            queued_room_ids.insert(room_id, idx);
+            order.push(idx);
+        }
+    }
+    store_queue(&queue);
+
+    let rooms_to_join = order.len();
+    let mut last_joins: HashMap<String, time::Instant> = HashMap::new();
+    for idx in order {
+        if is_past(options.deadline) {
+            eprintln!(
+                "Stopped joining at the deadline, {} rooms stay in the join queue.",
+                queue.iter().filter(|room| room.is_unfinished()).count()
+            );
+            break;
+        }
+        let room = &mut queue[idx];
+        let room_id = room.room_id.clone().unwrap();
+        let resolving = resolve_times.get(&idx).cloned().unwrap_or_default();
+        let started = time::Instant::now();
+
+        // if the bot is not yet in that room, and was not invited (which was already handled), and
+        // has not left that room, i.e. was kicked from that room, try to join.
+        let outcome = if ignore.ignores_room(&room_id) {
+            eprintln!("ignoring {}", room_id);
+            room.state = JoinState::Ignored;
+            Some(JoinOutcome::Ignored)
+        } else if let Some(kick) = kicks.get(&room_id) {
+            eprintln!("was kicked from room {}.", room_id);
+            if kick.banned {
+                room.state = JoinState::Banned;
+                Some(JoinOutcome::PreviouslyBanned)
+            } else {
+                room.state = JoinState::Kicked;
+                Some(JoinOutcome::PreviouslyKicked)
+            }
+        } else if joined_rooms_set.contains(&room_id) {
+            eprintln!("already joined room {}.", room_id);
+            room.state = JoinState::Joined;
+            Some(JoinOutcome::AlreadyMember)
+        } else if accepted_invites.contains(&room_id) {
+            // stays resolved, the next sync tells whether following the invite worked
+            eprintln!("already invited to room {}.", room_id);
+            Some(JoinOutcome::Invited)
+        } else if dry_run {
+            join_count += 1;
+            println!("join {} ({})", room.room, room_id);
+            Some(JoinOutcome::WouldJoin)
+        } else {
+            None
+        };
+        if let Some(outcome) = outcome {
+            reports.push(JoinReport::new(
+                room.room.clone(),
+                Some(room_id),
+                outcome,
+                resolving + started.elapsed(),
+            ));
+        } else {
+            // the alias or id server most likely is in the room, and probably the only one for small rooms
+            let server = server_name(&room.room);
+            if let Some(last_join) = last_joins.get(&server) {
+                let now = time::Instant::now();
+                let next_join = *last_join + options.server_interval;
+                if next_join > now {
+                    eprintln!("Waiting {:?} before joining another room of {}.", next_join - now, server);
+                    Delay::new(next_join - now).await.expect("wait failed");
+                }
+            }
+            let joining = time::Instant::now();
+            let join = join_room(client.clone(), room.room.clone(), &room.via).await;
+            last_joins.insert(server, time::Instant::now());
+            let elapsed = resolving + joining.elapsed();
+            let report = match join {
+                Ok(_) => {
+                    join_count += 1;
+                    room.state = JoinState::Joined;
+                    eprintln!(
+                        "Joined room: {} ({}/{})",
+                        room.room, join_count, rooms_to_join
+                    );
+                    JoinReport::new(room.room.clone(), Some(room_id), JoinOutcome::Joined, elapsed)
+                },
+                Err(e) => {
+                    eprintln!("Error joining room {}: {:?}", room_id, e);
+                    room.fail(&e);
+                    JoinReport::failed(room.room.clone(), Some(room_id), &e, elapsed)
+                },
+            };
+            reports.push(report);
+        }
+        store_queue(&queue);
+    }
+    Ok(JoinSummary {
+        join_count,
+        invite_count,
+        leave_count: rooms.leave.len(),
+        reports,
+    })
+}
+
+/// whether the deadline, if there is one, has passed
+fn is_past(deadline: Option<time::Instant>) -> bool {
+    deadline.map_or(false, |deadline| time::Instant::now() >= deadline)
+}
+
+const DIRECTORY_PAGE_SIZE: u32 = 100;
+static DISCOVERY_PATH: &str = "data/discovery.json";
+
+/// A room found in public room directories.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DiscoveredRoom {
+    pub room_id: RoomId,
+    /// canonical alias, or any other alias if the room has no canonical one
+    pub alias: Option<RoomAliasId>,
+    /// server names of the directories listing the room
+    pub directories: Vec<String>,
+    /// joined members as listed by the directories, the largest count if they disagree
+    #[serde(default)]
+    pub joined_members: Option<u64>,
+}
+
+/// the server name part of a user id or room alias, which only contains the port if the id does
+fn server_name(id: &impl fmt::Display) -> String {
+    id.to_string().splitn(2, ':').nth(1).unwrap().to_owned()
+}
+
+/// pages through the public room directory of the given server, or of our homeserver if none is given
+async fn public_rooms(
+    client: HttpsClient,
+    server: Option<String>,
+) -> Result<Vec<(RoomId, Option<RoomAliasId>, u64)>, ruma_client::Error> {
+    let mut rooms = Vec::new();
+    let mut since: Option<String> = None;
+    loop {
+        // the public room directories of other servers are federation requests
+        let response = pacing::DIRECTORY.request(|| client.request(
+            r0::directory::get_public_rooms::Request {
+                limit: Some(DIRECTORY_PAGE_SIZE.into()),
+                since: since.clone(),
+                server: server.clone(),
+            }
+        )).await?;
+        let is_last_page = response.chunk.is_empty();
+        rooms.extend(response.chunk.into_iter().map(|room| {
+            let alias = room
+                .canonical_alias
+                .or_else(|| room.aliases.and_then(|aliases| aliases.into_iter().next()));
+            (room.room_id, alias, room.num_joined_members.into())
+        }));
+        match response.next_batch {
+            Some(next_batch) if !is_last_page && since.as_ref() != Some(&next_batch) => {
+                since = Some(next_batch);
+            },
+            _ => break,
+        }
+    }
+    Ok(rooms)
+}
+
+/// Names of the servers with users in the joined rooms.
+async fn crawled_servers(
+    client: HttpsClient,
+    ignore: &IgnoreFilter,
+) -> Result<Vec<String>, ruma_client::Error> {
+    let mut servers = HashSet::new();
+    for room in joined_rooms(client.clone()).await? {
+        let members = match room_members(client.clone(), room.clone()).await {
+            Ok(members) => members,
+            Err(e) => {
+                eprintln!("error getting members of room {}: {:?}", room, e);
+                continue;
+            },
+        };
+        for (member, _) in members {
+            if ignore.ignores_user(&member) {
+                continue;
+            }
+            let user_id = match UserId::try_from(member.as_str()) {
+                Ok(user_id) => user_id,
+                Err(_) => continue,
+            };
+            servers.insert(server_name(&user_id));
+        }
+    }
+    let mut servers = Vec::from_iter(servers);
+    servers.sort();
+    Ok(servers)
+}
+
+fn store_discovery(discovered: &[DiscoveredRoom]) -> Result<(), io::Error> {
+    let path = Path::new(DISCOVERY_PATH);
+    fs::create_dir_all(path.parent().unwrap())?;
+    let file = fs::File::create(path)?;
+    let mut writer = io::BufWriter::new(file);
+    serde_json::to_writer_pretty(&mut writer, discovered).expect("Could not serialize discovered rooms");
+    writer.flush()
+}
+
+/// Collects the rooms listed in the public room directory of our homeserver
+/// and of all servers seen in the joined rooms, deduplicated by room id.
+/// The result is also stored in data/discovery.json.
+/// Servers whose users are ignored are not asked.
+pub async fn discover_rooms(
+    client: HttpsClient,
+    ignore_rules: &IgnoreRules,
+) -> Result<Vec<DiscoveredRoom>, ruma_client::Error> {
+    let ignore = IgnoreFilter::new(ignore_rules).expect("invalid ignore pattern");
+    let own_server = server_name(&client.session().expect("not logged in").user_id);
+    eprintln!("Collecting servers from the joined rooms…");
+    let servers = crawled_servers(client.clone(), &ignore).await?;
+    eprintln!("Found {} servers", servers.len());
+
+    let directories = std::iter::once(None)
+        .chain(servers.into_iter().filter(|server| server != &own_server).map(Some));
+    let mut discovered: HashMap<RoomId, DiscoveredRoom> = HashMap::new();
+    for server in directories {
+        let directory = server.clone().unwrap_or_else(|| own_server.clone());
+        match public_rooms(client.clone(), server).await {
+            Ok(rooms) => {
+                eprintln!("{} rooms in the directory of {}", rooms.len(), directory);
+                for (room_id, alias, joined_members) in rooms {
+                    let room = discovered.entry(room_id.clone()).or_insert_with(|| DiscoveredRoom {
+                        room_id,
+                        alias: None,
+                        directories: Vec::new(),
+                        joined_members: None,
+                    });
+                    if room.alias.is_none() {
+                        room.alias = alias;
+                    }
+                    room.joined_members = cmp::max(room.joined_members, Some(joined_members));
+                    if !room.directories.contains(&directory) {
+                        room.directories.push(directory.clone());
+                    }
+                }
+            },
+            Err(e) => eprintln!("Could not read the room directory of {}: {:?}", directory, e),
+        }
+    }
+
+    let mut discovered: Vec<DiscoveredRoom> = discovered.into_iter().map(|(_, room)| room).collect();
+    discovered.sort_by_key(|room| room.room_id.to_string());
+    store_discovery(&discovered).expect("Could not store discovered rooms");
+    let mut inventory = load_inventory();
+    for room in &discovered {
+        if let Some(alias) = &room.alias {
+            inventory.add(room.room_id.clone(), alias.clone());
+        }
+    }
+    store_inventory(&inventory).expect("Could not store inventory");
+    Ok(discovered)
+}
+
+fn load_discovery() -> Vec<DiscoveredRoom> {
+    match fs::File::open(DISCOVERY_PATH) {
+        Ok(file) => {
+            let reader = io::BufReader::new(file);
+            serde_json::from_reader(reader).expect("Could not deserialize discovered rooms")
+        },
+        Err(_) => Vec::new(),
+    }
+}
+
+/// The discovered rooms that are not joined yet. Rooms without any alias
+/// are joined by id through the servers whose directories list them.
+pub fn discovered_join_targets(
+    discovered: &[DiscoveredRoom],
+    joined_rooms: &HashSet<RoomId>,
+) -> Vec<JoinTarget> {
+    discovered
+        .iter()
+        .filter(|room| !joined_rooms.contains(&room.room_id))
+        .map(|room| match &room.alias {
+            Some(alias) => JoinTarget::new(RoomIdOrAliasId::RoomAliasId(alias.clone())),
+            None => JoinTarget {
+                room: RoomIdOrAliasId::RoomId(room.room_id.clone()),
+                via: room.directories.clone(),
+            },
+        })
+        .collect()
+}
+
+lazy_static! {
+    // room aliases in free text, e.g. in topics or in matrix.to links
+    static ref ALIAS_PATTERN: regex::Regex =
+        regex::Regex::new(r"#[^\s:#/]+:[A-Za-z0-9.\-]+(:[0-9]+)?").unwrap();
+    // matrix.to links with percent-encoded aliases, which ALIAS_PATTERN can't find
+    static ref ENCODED_MATRIX_TO_PATTERN: regex::Regex =
+        regex::Regex::new(r"matrix\.to/#/(%23[^\s/?#\x22'<>]+)").unwrap();
+}
+
+/// valid room aliases in the given text
+fn alias_mentions(text: &str) -> Vec<RoomAliasId> {
+    let encoded = ENCODED_MATRIX_TO_PATTERN.captures_iter(text).map(|captures| {
+        // only the characters relevant for aliases, as the server name has to be ascii anyway
+        captures[1].replace("%23", "#").replace("%3A", ":").replace("%3a", ":")
+    });
+    ALIAS_PATTERN
+        .find_iter(text)
+        // a mention at the end of a sentence
+        .map(|alias| alias.as_str().trim_end_matches('.').to_owned())
+        .chain(encoded)
+        .filter_map(|alias| RoomAliasId::try_from(alias.as_str()).ok())
+        .collect()
+}
+
+/// Room aliases mentioned in the canonical alias, topic and pinned events of the given room.
+/// The room needs to be joined or world-readable.
+async fn room_alias_mentions(client: HttpsClient, room_id: RoomId) -> Vec<RoomAliasId> {
+    // mining is best effort, state that could not be read has no mentions
+    let state = |event_type| room_state_content(client.clone(), room_id.clone(), event_type, String::new());
+    let canonical_alias = state(EventType::RoomCanonicalAlias).await.unwrap_or_default();
+    let topic = state(EventType::RoomTopic).await.unwrap_or_default();
+    let pinned_events = state(EventType::RoomPinnedEvents).await.unwrap_or_default();
+
+    let mut aliases = Vec::new();
+    if let Some(canonical_alias) = canonical_alias {
+        let alt_aliases = canonical_alias["alt_aliases"].as_array().cloned().unwrap_or_default();
+        aliases.extend(
+            std::iter::once(&canonical_alias["alias"])
+                .chain(alt_aliases.iter())
+                .filter_map(|alias| RoomAliasId::try_from(alias.as_str()?).ok()),
+        );
+    }
+    if let Some(topic) = state_field::<String>(&topic, "topic") {
+        aliases.extend(alias_mentions(&topic));
+    }
+    let pinned: Vec<String> = state_field(&pinned_events, "pinned").unwrap_or_default();
+    for event_id in pinned.iter().filter_map(|event_id| EventId::try_from(event_id.as_str()).ok()) {
+        let response = pacing::LOCAL.request(|| client.request(
+            r0::context::get_context::Request {
+                room_id: room_id.clone(),
+                event_id: event_id.clone(),
+                limit: Some(0u32.into()),
+            }
+        )).await;
+        let event = match response.map(|response| response.event.into_result()) {
+            Ok(Ok(event)) => serde_json::to_value(event).unwrap_or_default(),
+            _ => continue,
+        };
+        for field in &["body", "formatted_body"] {
+            if let Some(text) = event["content"][field].as_str() {
+                aliases.extend(alias_mentions(text));
+            }
+        }
+    }
+    aliases
+}
+
+/// Snowball discovery: collects the room aliases mentioned in the joined rooms,
+/// and up to the given depth in the rooms those aliases lead to, which can only be read if they're world-readable.
+/// Returns a single alias for each room that is not joined yet.
+pub async fn mine_aliases(
+    client: HttpsClient,
+    depth: usize,
+) -> Result<Vec<RoomAliasId>, ruma_client::Error> {
+    let joined_rooms = joined_rooms(client.clone()).await?;
+    let mut known_rooms: HashSet<RoomId> = HashSet::from_iter(joined_rooms.iter().cloned());
+    let mut known_aliases: HashSet<RoomAliasId> = HashSet::new();
+    let mut candidates = Vec::new();
+
+    let mut rooms_to_mine = joined_rooms;
+    for level in 1..=depth {
+        let mut found_rooms = Vec::new();
+        for (room_count, room) in rooms_to_mine.iter().enumerate() {
+            for alias in room_alias_mentions(client.clone(), room.clone()).await {
+                if !known_aliases.insert(alias.clone()) {
+                    continue;
+                }
+                match resolve_alias(client.clone(), alias.clone()).await {
+                    Ok(room_id) => {
+                        if known_rooms.insert(room_id.clone()) {
+                            candidates.push(alias);
+                            found_rooms.push(room_id);
+                        }
+                    },
+                    Err(e) => eprintln!("Could not resolve room {}: {:?}", alias, e),
+                }
+            }
+            eprintln!(
+                "Mined {}/{} rooms at depth {}, found {} new rooms so far",
+                room_count + 1,
+                rooms_to_mine.len(),
+                level,
+                candidates.len()
+            );
+        }
+        rooms_to_mine = found_rooms;
+    }
+    Ok(candidates)
+}
+
+/// Joins the given space and the rooms it contains, skipping rooms that were already joined
+/// or that the bot was kicked from. Returns the number of joined rooms and the number of rooms in the space.
+pub async fn join_space(
+    client: HttpsClient,
+    space: JoinTarget,
+    ignore_rules: &IgnoreRules,
+    dry_run: bool,
+) -> Result<(usize, usize), ruma_client::Error> {
+    let ignore = IgnoreFilter::new(ignore_rules).expect("invalid ignore pattern");
+    eprintln!("Syncing…");
+    let (rooms, _) = sync_rooms(client.clone()).await.expect("error syncing");
+    let kicked_rooms: HashSet<RoomId> =
+        honor_kicks(client.clone(), rooms.leave.keys().cloned().collect(), dry_run)
+            .await?
+            .into_iter()
+            .map(|kick| kick.room_id)
+            .collect();
+
+    let space_id = into_room_id(client.clone(), space.room.clone()).await?;
+    if !rooms.join.contains_key(&space_id) {
+        if dry_run {
+            // the rooms in the space are only known after joining it
+            println!("join space {} ({})", space.room, space_id);
+            return Ok((0, 0));
+        }
+        join_room(client.clone(), space.room.clone(), &space.via).await?;
+        eprintln!("Joined space: {}", space_id);
+    }
+
+    // the space's state can only be read after joining it
+    let state_events = room_state_events(client.clone(), space_id.clone()).await?;
+    let children = space_relations(&state_events, "m.space.child");
+    let rooms_to_join = children.len();
+    let mut join_count = 0;
+    for (room_id, via) in children {
+        if rooms.join.contains_key(&room_id) || kicked_rooms.contains(&room_id) {
+            eprintln!("already joined or was kicked from room {}.", room_id);
+            continue;
+        }
+        if ignore.ignores_room(&room_id) {
+            eprintln!("ignoring {}", room_id);
+            continue;
+        }
+        if dry_run {
+            join_count += 1;
+            println!("join {}", room_id);
+            continue;
+        }
+        match join_room(client.clone(), RoomIdOrAliasId::RoomId(room_id.clone()), &via).await {
+            Ok(_) => {
+                join_count += 1;
+                eprintln!("Joined room: {} ({}/{})", room_id, join_count, rooms_to_join);
+            },
+            Err(e) => eprintln!("Error joining room {}: {:?}", room_id, e),
+        };
+    }
+    Ok((join_count, rooms_to_join))
+}
+
+async fn leave_and_forget_room(
+    client: HttpsClient,
+    room_id: RoomId,
+) -> Result<(), ruma_client::Error> {
+    pacing::LOCAL.request(|| client.request(
+        r0::membership::leave_room::Request {
+            room_id: room_id.clone(),
+        }
+    )).await?;
+
+    pacing::LOCAL.request(|| client.request(
+        r0::membership::forget_room::Request { room_id: room_id.clone() }
+    )).await?;
+    Ok(())
+}
+
+pub async fn resolve_alias(
+    client: HttpsClient,
+    room_alias: RoomAliasId,
+) -> Result<RoomId, ruma_client::Error> {
+    let response = pacing::DIRECTORY.request(|| client.request(
+        r0::alias::get_alias::Request { room_alias: room_alias.clone() }
+    )).await?;
+    Ok(response.room_id)
+}
+
+pub async fn into_room_id(
+    client: HttpsClient,
+    room_id_or_alias_id: RoomIdOrAliasId,
+) -> Result<RoomId, ruma_client::Error> {
+    match room_id_or_alias_id {
+        RoomIdOrAliasId::RoomId(room_id) => Ok(room_id),
+        RoomIdOrAliasId::RoomAliasId(alias) => resolve_alias(client.clone(), alias).await,
+    }
+}
+
+/// Joins the room through the given servers, trying one after the other until a join succeeds.
+/// Without servers, the homeserver has to find a server in the room on its own, which only works for aliases.
+async fn join_room(
+    client: HttpsClient,
+    room: RoomIdOrAliasId,
+    via: &[String],
+) -> Result<RoomId, ruma_client::Error> {
+    let servers: Vec<Option<String>> = if via.is_empty() {
+        vec![None]
+    } else {
+        via.iter().cloned().map(Some).collect()
+    };
+    let mut last_error = None;
+    for server in servers {
+        match pacing::FEDERATION.request(|| client.request(
+            api::join_room_via::Request {
+                room_id_or_alias: room.clone(),
+                server_name: server.clone(),
+            }
+        )).await {
+            Ok(response) => return Ok(response.room_id),
+            Err(e) => {
+                if let Some(server) = server {
+                    eprintln!("Could not join room {} through {}: {:?}", room, server, e);
+                }
+                last_error = Some(e);
+            },
+        }
+    }
+    Err(last_error.unwrap())
+}
+
+fn membership(state: &MembershipState) -> Option<Membership> {
+    match state {
+        MembershipState::Join => Some(Membership::Join),
+        MembershipState::Invite => Some(Membership::Invite),
+        MembershipState::Leave => Some(Membership::Leave),
+        MembershipState::Ban => Some(Membership::Ban),
+        MembershipState::Knock => Some(Membership::Knock),
+        #[allow(unreachable_patterns)]
+        _ => None,
+    }
+}
+
+/// delivers the user ids of all users with a membership in the given room, together with their membership state
+async fn room_members(
+    client: HttpsClient,
+    room_id: RoomId,
+) -> Result<Vec<(String, Membership)>, ruma_client::Error> {
+    let response = pacing::LOCAL.request(|| client.request(
+        r0::sync::get_member_events::Request {
+            room_id: room_id.clone(),
+        }
+    )).await?;
+
+    // in the case of join membership events it's probably always the case that sender is the same user
+    // the event relates to, but actually, the state key is the field building the relationship to the user.
+    let members = response
+        .chunk
+        .into_iter()
+        .filter_map(|event_result| event_result.into_result().ok())
+        .filter_map(|event| {
+            membership(&event.content.membership).map(|membership| (event.state_key, membership))
+        })
+        .collect();
+    Ok(members)
+}
+
+/// delivers the content of the given room state event, or None if the room has no such state event
+async fn room_state_content(
+    client: HttpsClient,
+    room_id: RoomId,
+    event_type: EventType,
+    state_key: String,
+) -> Result<Option<serde_json::Value>, ruma_client::Error> {
+    let response = pacing::LOCAL.request(|| client.request(
+        r0::sync::get_state_events_for_key::Request {
+            room_id: room_id.clone(),
+            event_type: event_type.clone(),
+            state_key: state_key.clone(),
+        }
+    ))
+    .await;
+    match response {
+        Ok(response) => Ok(Some(response.content)),
+        // missing state events result in a M_NOT_FOUND error, which is not worth reporting
+        Err(e) if is_not_found(&e) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// parses a field of a state event content, None if the event or field is missing or unknown
+fn state_field<T: DeserializeOwned>(content: &Option<serde_json::Value>, field: &str) -> Option<T> {
+    content
+        .as_ref()
+        .and_then(|content| serde_json::from_value(content[field].clone()).ok())
+}
+
+/// what the crawl takes from the room state
+struct RoomState {
+    attributes: RoomAttributes,
+    /// the room this room replaced, from the create event
+    predecessor: Option<RoomId>,
+    /// the room that replaced this room, from the tombstone event
+    successor: Option<RoomId>,
+    /// rooms of a space, from its m.space.child events
+    space_children: Vec<RoomId>,
+    /// spaces this space is part of, from its m.space.parent events
+    space_parents: Vec<RoomId>,
+}
+
+/// state events of the given room as json, as ruma does not know about all event types
+async fn room_state_events(
+    client: HttpsClient,
+    room_id: RoomId,
+) -> Result<Vec<serde_json::Value>, ruma_client::Error> {
+    let response = pacing::LOCAL.request(|| client.request(
+        r0::sync::get_state_events::Request { room_id: room_id.clone() }
+    )).await?;
+    Ok(response
+        .room_state
+        .into_iter()
+        .filter_map(|event_result| event_result.into_result().ok())
+        .filter_map(|event| serde_json::to_value(event).ok())
+        .collect())
+}
+
+/// The rooms referenced by space state events of the given type, together with their via servers.
+/// Events without via servers are removed relations.
+fn space_relations(
+    state_events: &[serde_json::Value],
+    event_type: &str,
+) -> Vec<(RoomId, Vec<String>)> {
+    state_events
+        .iter()
+        .filter(|event| event["type"] == event_type)
+        .filter_map(|event| {
+            let room_id = RoomId::try_from(event["state_key"].as_str()?).ok()?;
+            let via: Vec<String> = event["content"]["via"]
+                .as_array()?
+                .iter()
+                .filter_map(|server| server.as_str().map(str::to_owned))
+                .collect();
+            if via.is_empty() {
+                None
+            } else {
+                Some((room_id, via))
+            }
+        })
+        .collect()
+}
+
+fn state_room_id(content: &Option<serde_json::Value>, pointer: &str) -> Option<RoomId> {
+    content
+        .as_ref()
+        .and_then(|content| content.pointer(pointer))
+        .and_then(serde_json::Value::as_str)
+        .and_then(|room_id| RoomId::try_from(room_id).ok())
+}
+
+/// Delivers the non-identifying properties and upgrade relations of the given room from its state.
+/// State events that could not be read are logged and left unknown, rather than taken as missing.
+async fn room_state(client: HttpsClient, room_id: RoomId) -> RoomState {
+    let state = |event_type: EventType| {
+        let client = client.clone();
+        let room_id = room_id.clone();
+        async move {
+            let content =
+                room_state_content(client, room_id.clone(), event_type.clone(), String::new()).await;
+            if let Err(e) = &content {
+                eprintln!("Could not read the {} state of room {}: {:?}", event_type, room_id, e);
+            }
+            content
+        }
+    };
+    let create = state(EventType::RoomCreate).await.unwrap_or_default();
+    let join_rules = state(EventType::RoomJoinRules).await.unwrap_or_default();
+    let history_visibility = state(EventType::RoomHistoryVisibility).await.unwrap_or_default();
+    let guest_access = state(EventType::RoomGuestAccess).await.unwrap_or_default();
+    let encrypted = state(EventType::RoomEncryption)
+        .await
+        .ok()
+        .map(|encryption| encryption.is_some());
+    let tombstone = state(EventType::RoomTombstone).await.unwrap_or_default();
+
+    // m.space.child and m.space.parent state keys are room ids, so the complete state is needed.
+    // Only spaces are asked for it, as regular rooms' m.space.parent events are repeated by the spaces.
+    let is_space = state_field::<String>(&create, "type").map_or(false, |kind| kind == "m.space");
+    let space_state = if is_space {
+        room_state_events(client.clone(), room_id.clone()).await.unwrap_or_default()
+    } else {
+        Vec::new()
+    };
+
+    let attributes = RoomAttributes {
+        // the room_version field is missing in rooms of version 1
+        room_version: create
+            .as_ref()
+            .map(|_| state_field(&create, "room_version").unwrap_or_else(|| "1".to_owned())),
+        join_rule: state_field(&join_rules, "join_rule"),
+        history_visibility: state_field(&history_visibility, "history_visibility"),
+        guest_access: state_field(&guest_access, "guest_access"),
+        encrypted,
+        space: is_space,
+        activity: None,
+    };
+    let space_rooms = |event_type| {
+        space_relations(&space_state, event_type)
+            .into_iter()
+            .map(|(room_id, _)| room_id)
+            .collect()
+    };
+    RoomState {
+        attributes,
+        predecessor: state_room_id(&create, "/predecessor/room_id"),
+        successor: state_room_id(&tombstone, "/replacement_room"),
+        space_children: space_rooms("m.space.child"),
+        space_parents: space_rooms("m.space.parent"),
+    }
+}
+
+// how far back the timeline of a room is sampled
+static ACTIVITY_WINDOW: time::Duration = time::Duration::from_secs(30 * 24 * 60 * 60);
+const DAY_MILLIS: u64 = 24 * 60 * 60 * 1000;
+// synapse doesn't deliver more events per /messages request anyway
+const ACTIVITY_PAGE_SIZE: usize = 100;
+
+/// Samples up to `samples` events of the room's timeline before the given sync token,
+/// going back at most ACTIVITY_WINDOW. Returns `None` if the timeline could not be read.
+async fn room_activity(
+    client: HttpsClient,
+    room_id: RoomId,
+    from: String,
+    samples: usize,
+) -> Option<RoomActivity> {
+    use r0::message::get_message_events;
+
+    let now = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .expect("system time is before the unix epoch")
+        .as_millis() as u64;
+    let window_start = now.saturating_sub(ACTIVITY_WINDOW.as_millis() as u64);
+
+    // (origin_server_ts, sender, is_state_event)
+    let mut events: Vec<(u64, String, bool)> = Vec::new();
+    let mut from = from;
+    // whether the sample covers the whole window, i.e. the sample size was not the limit
+    let mut covers_window = false;
+    while events.len() < samples {
+        let limit = cmp::min(ACTIVITY_PAGE_SIZE, samples - events.len()) as u32;
+        let response = pacing::LOCAL.request(|| client.request(
+            get_message_events::Request {
+                room_id: room_id.clone(),
+                from: from.clone(),
+                to: None,
+                dir: get_message_events::Direction::Backward,
+                limit: Some(limit.into()),
+            }
+        )).await;
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                eprintln!("error sampling the timeline of room {}: {:?}", room_id, e);
+                return None;
+            },
+        };
+
+        let page: Vec<(u64, String, bool)> = response
+            .chunk
+            .iter()
+            .filter_map(|event| match event {
+                EventResult::Ok(event) => serde_json::to_value(event).ok(),
+                _ => None,
+            })
+            .filter_map(|event| {
+                let timestamp = event["origin_server_ts"].as_u64()?;
+                let sender = event["sender"].as_str()?.to_owned();
+                Some((timestamp, sender, event.get("state_key").is_some()))
+            })
+            .collect();
+        let reached_window_start = page.iter().any(|&(timestamp, _, _)| timestamp < window_start);
+        events.extend(page.into_iter().filter(|&(timestamp, _, _)| timestamp >= window_start));
+        // an empty page or a repeated token means that the beginning of the room was reached
+        if reached_window_start || response.chunk.is_empty() || response.end == from {
+            covers_window = true;
+            break;
+        }
+        from = response.end;
+    }
+
+    let sample_start = if covers_window {
+        window_start
+    } else {
+        events.iter().map(|&(timestamp, _, _)| timestamp).min().unwrap_or(window_start)
+    };
+    // at least a day, so that a burst of events in a busy room doesn't result in absurd rates
+    let days = cmp::max(now.saturating_sub(sample_start), DAY_MILLIS) as f64 / DAY_MILLIS as f64;
+
+    let mut senders_by_day: HashMap<u64, HashSet<&str>> = HashMap::new();
+    for (timestamp, sender, _) in &events {
+        senders_by_day
+            .entry(now.saturating_sub(*timestamp) / DAY_MILLIS)
+            .or_default()
+            .insert(sender.as_str());
+    }
+    let sender_days: usize = senders_by_day.values().map(HashSet::len).sum();
+    let state_events = events.iter().filter(|&&(_, _, is_state_event)| is_state_event).count();
+
+    Some(RoomActivity {
+        events_per_day: events.len() as f64 / days,
+        senders_per_day: sender_days as f64 / days,
+        state_event_share: if events.is_empty() {
+            0.0
+        } else {
+            state_events as f64 / events.len() as f64
+        },
+    })
+}
+
+fn hash(builder: &dyn BuildHasher<Hasher = DefaultHasher>, x: &impl Hash) -> u64 {
+    let mut hasher = builder.build_hasher();
+    x.hash(&mut hasher);
+    hasher.finish()
+}
+
+static CHECKPOINT_PATH: &str = "data/crawl_checkpoint.json";
+static BASELINE_PATH: &str = "data/crawl_baseline.json";
+// storing the checkpoint after every room would mean serializing the whole partial graph thousands of times
+static CHECKPOINT_INTERVAL: usize = 50;
+// percentage of the joined rooms a crawl cut short at its deadline needs to have visited to write a graph
+const MIN_PARTIAL_COVERAGE: usize = 80;
+// resumed and incremental crawls reuse the hash key of the crawl they continue,
+// but no longer than a trip could last
+static BASELINE_MAX_AGE: time::Duration = time::Duration::from_secs(7 * 24 * 60 * 60);
+
+/// The pseudonymized state of a crawl.
+/// While crawling, it is stored as checkpoint to continue an interrupted crawl.
+/// After crawling, it is kept as baseline for the next incremental crawl, together with the sync token.
+/// All IDs in here are already pseudonymized, but with the stored hash key,
+/// anyone can hash candidate IDs and find them in the graph. So it's kept locally in data/
+/// and never published, and every full crawl starts with a new key that is used
+/// for at most BASELINE_MAX_AGE, after which an incremental crawl becomes a full crawl.
+#[derive(Serialize, Deserialize)]
+struct CrawlState {
+    hash_key: HashKey,
+    /// when the hash key was chosen, states without it are treated as expired
+    #[serde(default = "expired")]
+    keyed_at: time::SystemTime,
+    visited_rooms: HashSet<u64>,
+    graph: matrixgraph::Graph,
+    /// sync token from the start of the crawl, membership changes after it are not part of the graph
+    #[serde(default)]
+    next_batch: Option<String>,
+    /// pseudonymized (source, target) room pairs of room upgrades and spaces,
+    /// which become edges once both rooms are part of the graph
+    #[serde(default)]
+    room_relations: HashSet<(u64, u64, Edge)>,
+    /// the rules the graph was crawled with, states without them were crawled with the default rules
+    #[serde(default)]
+    ignore_rules: IgnoreRules,
+    // can be rebuilt from the graph, as each node is identified by its kind and pseudonymized id
+    #[serde(skip)]
+    room_indexes: HashMap<u64, NodeIndex>,
+    #[serde(skip)]
+    user_indexes: HashMap<u64, NodeIndex>,
+    #[serde(skip)]
+    server_indexes: HashMap<u64, NodeIndex>,
+}
+
+impl CrawlState {
+    fn new(ignore_rules: IgnoreRules) -> Self {
+        CrawlState {
+            // pseudonymization:
+            // on each crawl, choose a different random hash function
+            hash_key: HashKey::random(),
+            keyed_at: time::SystemTime::now(),
+            visited_rooms: HashSet::new(),
+            graph: Graph::new_undirected(),
+            next_batch: None,
+            room_relations: HashSet::new(),
+            ignore_rules,
+            room_indexes: HashMap::new(),
+            user_indexes: HashMap::new(),
+            server_indexes: HashMap::new(),
+        }
+    }
+
+    fn rebuild_indexes(&mut self) {
+        self.room_indexes.clear();
+        self.user_indexes.clear();
+        self.server_indexes.clear();
+        for idx in self.graph.node_indices() {
+            let node = &self.graph[idx];
+            let indexes = match node.kind {
+                NodeType::Room => &mut self.room_indexes,
+                NodeType::User => &mut self.user_indexes,
+                NodeType::Server => &mut self.server_indexes,
+            };
+            indexes.insert(node.id, idx);
+        }
+    }
+
+    /// whether the hash key is too old to pseudonymize another crawl with it
+    fn is_expired(&self) -> bool {
+        self.keyed_at.elapsed().map_or(true, |age| age > BASELINE_MAX_AGE)
+    }
+
+    fn is_visited(&self, room: &RoomId) -> bool {
+        self.visited_rooms.contains(&hash(&self.hash_key, room))
+    }
+
+    fn contains_room(&self, room: &RoomId) -> bool {
+        self.room_indexes.contains_key(&hash(&self.hash_key, room))
+    }
+
+    fn add_room(
+        &mut self,
+        room: &RoomId,
+        members: Vec<(String, Membership)>,
+        state: RoomState,
+        ignore: &IgnoreFilter,
+        user_classifier: &UserClassifier,
+    ) {
+        let room_hash = hash(&self.hash_key, room);
+        let hash_key = &self.hash_key;
+        let relations = state
+            .predecessor
+            .iter()
+            .map(|predecessor| (hash(hash_key, predecessor), room_hash, Edge::Successor))
+            .chain(state.successor.iter().map(|successor| {
+                (room_hash, hash(hash_key, successor), Edge::Successor)
+            }))
+            .chain(state.space_children.iter().map(|child| {
+                (room_hash, hash(hash_key, child), Edge::SpaceChild)
+            }))
+            .chain(state.space_parents.iter().map(|parent| {
+                (hash(hash_key, parent), room_hash, Edge::SpaceChild)
+            }));
+        self.room_relations.extend(relations);
+        for (member, membership) in members {
+            if ignore.ignores_user(&member) {
+                continue;
+            }
+            // if we came as far as here, there's at least one non-ignored user in that room, and
+            // we can add it to the graph.
+            let graph = &mut self.graph;
+            let room_idx = *self.room_indexes.entry(room_hash).or_insert_with(|| {
+                graph.add_node(Node {
+                    kind: NodeType::Room,
+                    id: room_hash,
+                    room: Some(state.attributes.clone()),
+                    user: None,
+                })
+            });
+            self.set_membership(room_idx, &member, membership, user_classifier);
+        }
+        self.visited_rooms.insert(room_hash);
+    }
+
+    /// applies a membership change to a room that is already part of the graph,
+    /// returns whether the graph changed
+    fn update_membership(
+        &mut self,
+        room: &RoomId,
+        member: &str,
+        membership: Membership,
+        ignore: &IgnoreFilter,
+        user_classifier: &UserClassifier,
+    ) -> bool {
+        if ignore.ignores_user(member) {
+            return false;
+        }
+        let room_idx = self.room_indexes[&hash(&self.hash_key, room)];
+        self.set_membership(room_idx, member, membership, user_classifier)
+    }
+
+    /// adds user and server of the member if necessary, returns whether the graph changed
+    fn set_membership(
+        &mut self,
+        room_idx: NodeIndex,
+        member: &str,
+        membership: Membership,
+        user_classifier: &UserClassifier,
+    ) -> bool {
+        let graph = &mut self.graph;
+        let user_id = UserId::try_from(member).unwrap();
+        let server_id = ServerId::new(&user_id);
+        let user_hash = hash(&self.hash_key, &user_id);
+        let server_hash = hash(&self.hash_key, &server_id);
+        let is_new_server = !self.server_indexes.contains_key(&server_hash);
+        let server_idx = *self.server_indexes.entry(server_hash).or_insert_with(|| {
+            graph.add_node(Node {
+                kind: NodeType::Server,
+                id: server_hash,
+                room: None,
+                user: None,
+            })
+        });
+
+        // is_new_server -> !user_indexes.contains_key,
+        // if this is a new server, it can't have users yet
+        debug_assert!(
+            !is_new_server || !self.user_indexes.contains_key(&user_hash),
+            "Server {} is new, but we already found User {}!",
+            server_id,
+            user_id
+        );
+        let user_idx = *self.user_indexes.entry(user_hash).or_insert_with(|| {
+            let user_idx = graph.add_node(Node {
+                kind: NodeType::User,
+                id: user_hash,
+                room: None,
+                user: None,
+            });
+            graph.add_edge(user_idx, server_idx, Edge::Account);
+            user_idx
+        });
+        // (re)classify known users as well, so that changed rules also apply to users of a baseline
+        graph[user_idx].user = Some(user_classifier.classify(member));
+
+        let edge = Edge::Member(membership);
+        let changed = match graph.find_edge(user_idx, room_idx) {
+            Some(edge_idx) => graph[edge_idx] != edge,
+            None => true,
+        };
+        graph.update_edge(user_idx, room_idx, edge);
+        // connect room and the user's server in case that edge was not yet there
+        graph.update_edge(server_idx, room_idx, Edge::Participation);
+        changed
+    }
+
+    /// adds edges for all room relations whose rooms are both part of the graph
+    fn link_room_relations(&mut self) {
+        for (source, target, edge) in &self.room_relations {
+            if let (Some(&source_idx), Some(&target_idx)) =
+                (self.room_indexes.get(source), self.room_indexes.get(target))
+            {
+                if source_idx != target_idx {
+                    self.graph.update_edge(source_idx, target_idx, *edge);
+                }
+            }
+        }
+    }
+
+    /// removes the given pseudonymized rooms together with users and servers only known through them
+    fn remove_rooms(&mut self, room_hashes: &HashSet<u64>) {
+        matrixgraph::remove_rooms(&mut self.graph, room_hashes);
+        self.visited_rooms.retain(|room_hash| !room_hashes.contains(room_hash));
+        self.rebuild_indexes();
+    }
+
+    fn summary(&self, unvisited_rooms: usize, written: bool) -> CrawlSummary {
+        let graph = &self.graph;
+        let joined_users: Vec<NodeIndex> = self
+            .user_indexes
+            .values()
+            .cloned()
+            .filter(|&idx| {
+                graph
+                    .edges(idx)
+                    .any(|edge| *edge.weight() == Edge::Member(Membership::Join))
+            })
+            .collect();
+        let joined_servers: HashSet<NodeIndex> = joined_users
+            .iter()
+            .flat_map(|&idx| {
+                graph
+                    .edges(idx)
+                    .filter(|edge| *edge.weight() == Edge::Account)
+                    .map(move |edge| if edge.source() == idx { edge.target() } else { edge.source() })
+            })
+            .collect();
+        CrawlSummary {
+            rooms: self.room_indexes.len(),
+            users: self.user_indexes.len(),
+            servers: self.server_indexes.len(),
+            joined_users: joined_users.len(),
+            joined_servers: joined_servers.len(),
+            unvisited_rooms,
+            written,
+        }
+    }
+
+    /// anonymizes the graph and writes it together with the given delta and the ignore rules,
+    /// so that it's known which rooms and users are missing on purpose.
+    /// The room fingerprints and the ignore rules themselves are kept locally in data/,
+    /// the graph directory only gets a digest of the rules.
+    /// Returns the graph directory.
+    fn write(&self, joined_rooms: &[RoomId], delta: Option<matrixgraph::GraphDelta>) -> PathBuf {
+        let anonymizer = matrixgraph::Anonymizer::new();
+        let graph = anonymizer.anonymize(&self.graph);
+
+        // anonymize keeps the node indices, which allows to find the anonymized room nodes
+        let mut fingerprints = matrixgraph::RoomFingerprints::new();
+        for room in joined_rooms {
+            if let Some(&idx) = self.room_indexes.get(&hash(&self.hash_key, room)) {
+                fingerprints.insert(room, graph[idx].id);
+            }
+        }
+
+        let dir = matrixgraph::graph_dir();
+        matrixgraph::write_graph(&graph, &dir).unwrap();
+        matrixgraph::write_room_fingerprints(&fingerprints, &dir).unwrap();
+        if let Some(delta) = delta {
+            matrixgraph::write_delta(&delta.anonymize(&anonymizer), &dir).unwrap();
+        }
+        matrixgraph::export_graph_to_dot(&graph, &dir).unwrap();
+        matrixgraph::export_graph_to_graphml(&graph, &dir).unwrap();
+        write_ignore_rules(&self.ignore_rules, &dir).unwrap();
+        dir
+    }
+}
+
+/// Marks the graph in the given directory as partial, as the crawl ran out of time.
+fn write_partial_marker(visited_rooms: usize, joined_rooms: usize, dir: &Path) -> Result<(), io::Error> {
+    let file = fs::File::create(dir.join("partial.json"))?;
+    let mut writer = io::BufWriter::new(file);
+    let marker = serde_json::json!({
+        "visited_rooms": visited_rooms,
+        "joined_rooms": joined_rooms,
+    });
+    serde_json::to_writer_pretty(&mut writer, &marker).expect("Could not serialize partial marker");
+    writer.flush()
+}
+
+/// The ignore rules a graph was crawled with, kept locally in data/ignore_rules,
+/// as they name the rooms, users and servers that opted out.
+#[derive(Serialize, Deserialize)]
+struct AppliedIgnoreRules {
+    /// random hex string hashed together with the rules, so that the digest can't be matched
+    /// against guessed rules
+    salt: String,
+    rules: IgnoreRules,
+}
+
+impl AppliedIgnoreRules {
+    fn new(rules: &IgnoreRules) -> Self {
+        let mut rng = rand::thread_rng();
+        AppliedIgnoreRules {
+            salt: format!("{:016x}{:016x}", rng.gen::<u64>(), rng.gen::<u64>()),
+            rules: rules.clone(),
+        }
+    }
+
+    fn digest(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.input(self.salt.as_bytes());
+        hasher.input(serde_json::to_vec(&self.rules).expect("Could not serialize ignore rules"));
+        format!("{:x}", hasher.result())
+    }
+}
+
+/// What the published graph tells about its ignore rules:
+/// a digest to check them against the local copy, and how many rules of each kind there were.
+#[derive(Serialize, Deserialize)]
+struct IgnoreRulesSummary {
+    digest: String,
+    room_aliases: usize,
+    room_ids: usize,
+    users: usize,
+    servers: usize,
+}
+
+/// where the ignore rules of the graph in the given graph directory are stored
+fn ignore_rules_path(graph_dir: &Path) -> PathBuf {
+    let name = graph_dir.file_name().expect("graph directory without name");
+    Path::new("data/ignore_rules").join(name).with_extension("json")
+}
+
+/// writes the ignore rules to data/ignore_rules and only their summary to the graph directory
+fn write_ignore_rules(rules: &IgnoreRules, dir: &Path) -> Result<(), io::Error> {
+    let applied = AppliedIgnoreRules::new(rules);
+    let path = ignore_rules_path(dir);
+    fs::create_dir_all(path.parent().unwrap())?;
+    let file = fs::File::create(path)?;
+    let mut writer = io::BufWriter::new(file);
+    serde_json::to_writer_pretty(&mut writer, &applied).expect("Could not serialize ignore rules");
+    writer.flush()?;
+
+    let summary = IgnoreRulesSummary {
+        digest: applied.digest(),
+        room_aliases: rules.room_aliases.len(),
+        room_ids: rules.room_ids.len(),
+        users: rules.users.len(),
+        servers: rules.servers.len(),
+    };
+    let file = fs::File::create(dir.join("ignore_rules.json"))?;
+    let mut writer = io::BufWriter::new(file);
+    serde_json::to_writer_pretty(&mut writer, &summary).expect("Could not serialize ignore rules");
+    writer.flush()
+}
+
+fn expired() -> time::SystemTime {
+    time::UNIX_EPOCH
+}
+
+fn load_crawl_state(path: &str) -> Result<CrawlState, io::Error> {
+    let file = fs::File::open(path)?;
+    let reader = io::BufReader::new(file);
+    let mut state: CrawlState =
+        serde_json::from_reader(reader).expect("Could not deserialize crawl state");
+    state.rebuild_indexes();
+    Ok(state)
+}
+
+fn store_crawl_state(state: &CrawlState, path: &str) -> Result<(), io::Error> {
+    let path = Path::new(path);
+    fs::create_dir_all(path.parent().unwrap())?;
+    // write to a temporary file first, so that being interrupted while writing
+    // doesn't destroy the previous state
+    let tmp_path = path.with_extension("json.tmp");
+    {
+        let file = fs::File::create(&tmp_path)?;
+        let mut writer = io::BufWriter::new(file);
+        serde_json::to_writer(&mut writer, state).expect("Could not serialize crawl state");
+        writer.flush()?;
+    }
+    fs::rename(tmp_path, path)
+}
+
+/// whether a previous crawl left a baseline for an incremental crawl
+pub fn has_crawl_baseline() -> bool {
+    Path::new(BASELINE_PATH).exists()
+}
+
+/// Rooms and users to leave out of joins and crawls, configurable in config.ron.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct IgnoreRules {
+    /// patterns of room aliases whose rooms are not joined
+    pub room_aliases: Vec<String>,
+    /// rooms that are neither joined nor crawled
+    pub room_ids: Vec<RoomId>,
+    /// patterns of user ids which are left out of the graph
+    pub users: Vec<String>,
+    /// server names whose users are left out of the graph
+    pub servers: Vec<String>,
+}
+
+impl Default for IgnoreRules {
+    fn default() -> Self {
+        IgnoreRules {
+            // The simulation will assume the same message sending behaviour for all users.
+            // So skip twitter rooms as the users in there also mirror twitter followers,
+            // they don't actually take part in matrix but are for display purposes only - they never send messages.
+            // This is in contrast to e.g. bridged discord or IRC users, so they're left inside the graph.
+            room_aliases: vec![r"^#twitter_#".to_string()],
+            room_ids: Vec::new(),
+            // ignore ourself and voyager, as we are in all rooms but silent, so we won't send messages in the simulation
+            users: vec![
+                r"^@.*:dsn-traveller\.dsn\.scc\.kit\.edu$".to_string(),
+                r"^@voyager:t2bot\.io$".to_string(),
+            ],
+            // weho.st and disroot.org requested to opt out as whole server, this will lead to an
+            // anonymized graph in which those servers and the users on them never existed.
+            servers: vec!["weho.st".to_string(), "disroot.org".to_string()],
+        }
+    }
+}
+
+pub struct IgnoreFilter {
+    room_aliases: regex::RegexSet,
+    room_ids: HashSet<RoomId>,
+    users: regex::RegexSet,
+    servers: HashSet<String>,
+}
+
+impl IgnoreFilter {
+    pub fn new(rules: &IgnoreRules) -> Result<Self, regex::Error> {
+        Ok(IgnoreFilter {
+            room_aliases: regex::RegexSet::new(&rules.room_aliases)?,
+            room_ids: HashSet::from_iter(rules.room_ids.iter().cloned()),
+            users: regex::RegexSet::new(&rules.users)?,
+            servers: HashSet::from_iter(rules.servers.iter().cloned()),
+        })
+    }
+
+    fn ignores_alias(&self, alias: &RoomAliasId) -> bool {
+        self.room_aliases.is_match(&alias.to_string())
+    }
+
+    fn ignores_room(&self, room_id: &RoomId) -> bool {
+        self.room_ids.contains(room_id)
+    }
+
+    fn ignores_user(&self, user_id: &str) -> bool {
+        self.users.is_match(user_id)
+            || (user_id.contains(':') && self.servers.contains(&server_name(&user_id)))
+    }
+}
+
+/// Which invites the bot follows, configurable in config.ron.
+/// Invites that are not followed are rejected.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InviteRules {
+    /// servers whose users' invites are followed, invites from all servers are followed if empty
+    pub allow_servers: Vec<String>,
+    /// patterns of server names whose users' invites are rejected, each matching the whole server name,
+    /// e.g. `matrix\.org` or `.*\.example\.org`
+    pub deny_servers: Vec<String>,
+    /// patterns of user ids whose invites are rejected
+    pub deny_users: Vec<String>,
+    /// Invites are only followed to rooms with more joined members than this.
+    /// An invite doesn't tell how many members a room has, so the count is taken from the public room
+    /// directories found by the last discover. If this is set, invites to unlisted rooms are rejected.
+    pub min_members: usize,
+}
+
+pub struct InvitePolicy {
+    allow_servers: HashSet<String>,
+    deny_servers: regex::RegexSet,
+    deny_users: regex::RegexSet,
+    min_members: usize,
+}
+
+impl InvitePolicy {
+    pub fn new(rules: &InviteRules) -> Result<Self, regex::Error> {
+        Ok(InvitePolicy {
+            allow_servers: HashSet::from_iter(rules.allow_servers.iter().cloned()),
+            deny_servers: regex::RegexSet::new(
+                rules.deny_servers.iter().map(|pattern| format!("^(?:{})$", pattern)),
+            )?,
+            deny_users: regex::RegexSet::new(&rules.deny_users)?,
+            min_members: rules.min_members,
+        })
+    }
+
+    /// the reason to reject an invite by the given user to a room with the given number of joined members,
+    /// if it's known
+    fn rejection(&self, inviter: Option<&UserId>, joined_members: Option<u64>) -> Result<(), String> {
+        let inviter = match inviter {
+            Some(inviter) => inviter,
+            None => return Err("inviter is unknown".to_string()),
+        };
+        let server = server_name(inviter);
+        if !self.allow_servers.is_empty() && !self.allow_servers.contains(&server) {
+            Err(format!("server {} is not allowed", server))
+        } else if self.deny_servers.is_match(&server) {
+            Err(format!("server {} is denied", server))
+        } else if self.deny_users.is_match(&inviter.to_string()) {
+            Err(format!("user {} is denied", inviter))
+        } else if self.min_members == 0 {
+            Ok(())
+        } else {
+            match joined_members {
+                None => {
+                    Err("number of members is unknown, the room is in no discovered directory".to_string())
+                },
+                Some(count) if count <= self.min_members as u64 => {
+                    Err(format!("only {} joined members", count))
+                },
+                Some(_) => Ok(()),
+            }
+        }
+    }
+}
+
+/// user id pattern of the appservice namespace of a bridge
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BridgeNamespace {
+    /// bridge family recorded on the user nodes, e.g. "irc"
+    pub bridge: String,
+    pub user_pattern: String,
+}
+
+impl BridgeNamespace {
+    fn new(bridge: &str, user_pattern: &str) -> Self {
+        BridgeNamespace {
+            bridge: bridge.to_string(),
+            user_pattern: user_pattern.to_string(),
+        }
+    }
+}
+
+/// User id patterns to classify users, configurable in config.ron.
+/// Users matching none of them are native users.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UserClassRules {
+    pub guests: Vec<String>,
+    pub bridges: Vec<BridgeNamespace>,
+    pub bots: Vec<String>,
+}
+
+impl Default for UserClassRules {
+    fn default() -> Self {
+        UserClassRules {
+            // synapse gives guests numeric localparts
+            guests: vec![r"^@[0-9]+:".to_string()],
+            bridges: vec![
+                BridgeNamespace::new("irc", r"^@_?(irc|freenode|oftc|libera|snoonet|gimpnet|mozilla)_"),
+                BridgeNamespace::new("discord", r"^@_discord_"),
+                BridgeNamespace::new("telegram", r"^@_?telegram_"),
+                BridgeNamespace::new("slack", r"^@_?slack_"),
+                BridgeNamespace::new("gitter", r"^@_?gitter_"),
+                BridgeNamespace::new("twitter", r"^@_twitter_"),
+                BridgeNamespace::new("whatsapp", r"^@_?whatsapp_"),
+                BridgeNamespace::new("signal", r"^@_?signal_"),
+            ],
+            bots: vec![r"(?i)^@[^:]*bot:".to_string()],
+        }
+    }
+}
+
+pub struct UserClassifier {
+    guests: regex::RegexSet,
+    bridges: Vec<(String, regex::Regex)>,
+    bots: regex::RegexSet,
+}
+
+impl UserClassifier {
+    pub fn new(rules: &UserClassRules) -> Result<Self, regex::Error> {
+        let bridges = rules
+            .bridges
+            .iter()
+            .map(|namespace| {
+                regex::Regex::new(&namespace.user_pattern)
+                    .map(|pattern| (namespace.bridge.clone(), pattern))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(UserClassifier {
+            guests: regex::RegexSet::new(&rules.guests)?,
+            bridges,
+            bots: regex::RegexSet::new(&rules.bots)?,
+        })
+    }
+
+    fn classify(&self, user_id: &str) -> UserClass {
+        if self.guests.is_match(user_id) {
+            return UserClass::Guest;
+        }
+        // bridges go before bots, as bridge puppets for bots should look like the bridge's other users
+        if let Some((bridge, _)) = self.bridges.iter().find(|(_, pattern)| pattern.is_match(user_id)) {
+            return UserClass::Bridged(bridge.clone());
+        }
+        if self.bots.is_match(user_id) {
+            return UserClass::Bot;
+        }
+        UserClass::Native
+    }
+}
+
+/// joined rooms without ignored ones and those the bot was kicked from,
+/// which are recorded from the left rooms of a sync
+async fn crawlable_rooms(
+    client: HttpsClient,
+    left_rooms: Vec<RoomId>,
+    ignore: &IgnoreFilter,
+) -> Result<Vec<RoomId>, ruma_client::Error> {
+    // Following an invite removes a kick from the record,
+    // so this only skips rooms which were joined again without the room asking for it.
+    let kicked_rooms: HashSet<RoomId> = honor_kicks(client.clone(), left_rooms, false)
+        .await?
+        .into_iter()
+        .map(|kick| kick.room_id)
+        .collect();
+
+    Ok(joined_rooms(client.clone())
+        .await?
+        .into_iter()
+        .filter(|room| !kicked_rooms.contains(room) && !ignore.ignores_room(room))
+        .collect())
+}
+
+pub struct CrawlOptions {
+    /// continue from the checkpoint of an interrupted crawl
+    pub resume: bool,
+    /// number of membership requests that are in flight at the same time
+    pub concurrency: usize,
+    /// minimum time between the start of two requests to the homeserver, shared by all in-flight requests.
+    /// Requests are sent that often unless the homeserver starts rate limiting.
+    pub request_interval: time::Duration,
+    /// how users are classified on the user nodes
+    pub user_classes: UserClassRules,
+    /// rooms and users that are left out of the graph
+    pub ignore_rules: IgnoreRules,
+    /// maximum number of recent timeline events sampled per room for its activity, 0 disables sampling
+    pub activity_samples: usize,
+    /// When a full crawl stops visiting rooms and writes the graph of the rooms visited so far,
+    /// marked as partial, if they are at least MIN_PARTIAL_COVERAGE percent of the joined rooms.
+    /// The checkpoint is kept, and the cut crawl doesn't become the baseline of incremental crawls.
+    pub deadline: Option<time::Instant>,
+}
+
+/// What a crawl found.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CrawlSummary {
+    pub rooms: usize,
+    /// users with any membership in the rooms, including invited, left and banned ones
+    pub users: usize,
+    /// servers of all those users
+    pub servers: usize,
+    /// users that are joined to at least one of the rooms
+    pub joined_users: usize,
+    /// servers with joined users
+    pub joined_servers: usize,
+    /// joined rooms the crawl didn't visit before its deadline
+    pub unvisited_rooms: usize,
+    /// whether the graph was written, a cut crawl that visited too few rooms only keeps its checkpoint
+    pub written: bool,
+}
+
+impl Default for CrawlOptions {
+    fn default() -> Self {
+        CrawlOptions {
+            resume: false,
+            concurrency: 4,
+            request_interval: pacing::LOCAL_MIN_INTERVAL,
+            user_classes: UserClassRules::default(),
+            ignore_rules: IgnoreRules::default(),
+            activity_samples: 500,
+            deadline: None,
+        }
+    }
+}
+
+/// members and state of the room, with the activity sampled from the timeline before the sync token
+async fn crawl_room(
+    client: HttpsClient,
+    room: RoomId,
+    sync_token: String,
+    activity_samples: usize,
+) -> Result<(Vec<(String, Membership)>, RoomState), ruma_client::Error> {
+    // occasionally this resulted in a bad gateway error
+    // could not find the synapse log lines for that, but it's probably due to server overload.
+    // redoing it once worked fine.
+    let members = match room_members(client.clone(), room.clone()).await {
+        Ok(members) => members,
+        Err(e) => {
+            eprintln!("error getting room members: {:?}, retrying once.", e);
+            room_members(client.clone(), room.clone()).await?
+        },
+    };
+    let mut state = room_state(client.clone(), room.clone()).await;
+    if activity_samples > 0 {
+        state.attributes.activity =
+            room_activity(client, room, sync_token, activity_samples).await;
+    }
+    Ok((members, state))
+}
+
+pub async fn crawl(
+    client: HttpsClient,
+    options: CrawlOptions,
+) -> Result<CrawlSummary, ruma_client::Error> {
+    let ignore = IgnoreFilter::new(&options.ignore_rules).expect("invalid ignore pattern");
+    let user_classifier =
+        UserClassifier::new(&options.user_classes).expect("invalid user class pattern");
+
+    let mut checkpoint = if options.resume {
+        match load_crawl_state(CHECKPOINT_PATH) {
+            Ok(checkpoint) if checkpoint.ignore_rules != options.ignore_rules => {
+                eprintln!("The ignore rules changed since the interrupted crawl, starting a new crawl.");
+                CrawlState::new(options.ignore_rules.clone())
+            },
+            Ok(checkpoint) if checkpoint.is_expired() => {
+                eprintln!("The hash key of the interrupted crawl expired, starting a new crawl.");
+                CrawlState::new(options.ignore_rules.clone())
+            },
+            Ok(checkpoint) => {
+                eprintln!(
+                    "Resuming crawl, {} rooms were already visited.",
+                    checkpoint.visited_rooms.len()
+                );
+                checkpoint
+            },
+            Err(e) => {
+                eprintln!("Could not load crawl checkpoint: {}, starting a new crawl.", e);
+                CrawlState::new(options.ignore_rules.clone())
+            },
+        }
+    } else {
+        CrawlState::new(options.ignore_rules.clone())
+    };
+
+    let (rooms, next_batch) = sync_rooms(client.clone()).await?;
+    // when resuming, changes since the start of the interrupted crawl might be missing already
+    if checkpoint.next_batch.is_none() {
+        checkpoint.next_batch = Some(next_batch);
+    }
+    let joined_rooms =
+        crawlable_rooms(client.clone(), rooms.leave.keys().cloned().collect(), &ignore).await?;
+
+    // rooms the bot was kicked from or has left since the interrupted crawl visited them
+    let joined_hashes: HashSet<u64> = joined_rooms
+        .iter()
+        .map(|room| hash(&checkpoint.hash_key, room))
+        .collect();
+    let gone_rooms: HashSet<u64> = checkpoint
+        .visited_rooms
+        .iter()
+        .filter(|room_hash| !joined_hashes.contains(*room_hash))
+        .cloned()
+        .collect();
+    if !gone_rooms.is_empty() {
+        eprintln!("Removing {} visited rooms the bot is no longer in.", gone_rooms.len());
+        checkpoint.remove_rooms(&gone_rooms);
+    }
+    let rooms_to_crawl = joined_rooms.len();
+    let unvisited_rooms: Vec<RoomId> = joined_rooms
+        .iter()
+        .filter(|room| !checkpoint.is_visited(room))
+        .cloned()
+        .collect();
+
+    // Only the membership and room state requests run concurrently.
+    // `buffered` yields their results in the order of the room list,
+    // so the graph is still assembled by this single loop in a deterministic order.
+    pacing::LOCAL.set_min_interval(options.request_interval);
+    let sync_token = checkpoint.next_batch.clone().unwrap();
+    let sync_token = &sync_token;
+    let activity_samples = options.activity_samples;
+    let mut room_results = Box::pin(
+        stream::iter(unvisited_rooms)
+            .map(|room| {
+                let client = client.clone();
+                async move {
+                    let room_data = crawl_room(
+                        client,
+                        room.clone(),
+                        sync_token.clone(),
+                        activity_samples,
+                    )
+                    .await;
+                    (room, room_data)
+                }
+            })
+            .buffered(cmp::max(options.concurrency, 1)),
+    );
+
+    let mut cut_short = false;
+    while let Some((room, room_data)) = room_results.next().await {
+        let (members, state) = match room_data {
+            Ok(room_data) => room_data,
+            Err(e) => {
+                store_crawl_state(&checkpoint, CHECKPOINT_PATH)
+                    .expect("Could not store crawl checkpoint");
+                eprintln!(
+                    "Stored crawl checkpoint after {}/{} rooms, continue with crawl --resume.",
+                    checkpoint.visited_rooms.len(),
+                    rooms_to_crawl
+                );
+                return Err(e);
+            },
+        };
+
+        checkpoint.add_room(
+            &room,
+            members,
+            state,
+            &ignore,
+            &user_classifier,
+        );
+        let crawled_rooms = checkpoint.visited_rooms.len();
+        if crawled_rooms % CHECKPOINT_INTERVAL == 0 {
+            store_crawl_state(&checkpoint, CHECKPOINT_PATH)
+                .expect("Could not store crawl checkpoint");
+        }
+        eprintln!("Crawled {}/{} rooms", crawled_rooms, rooms_to_crawl);
+        if is_past(options.deadline) {
+            cut_short = crawled_rooms < rooms_to_crawl;
+            break;
+        }
+    }
+
+    let visited_rooms = checkpoint.visited_rooms.len();
+    let unvisited_rooms = if cut_short { rooms_to_crawl - visited_rooms } else { 0 };
+    if cut_short {
+        store_crawl_state(&checkpoint, CHECKPOINT_PATH).expect("Could not store crawl checkpoint");
+        if visited_rooms * 100 < rooms_to_crawl * MIN_PARTIAL_COVERAGE {
+            eprintln!(
+                "The crawl ran out of time after {}/{} rooms, too few to write a graph.",
+                visited_rooms, rooms_to_crawl
+            );
+            return Ok(checkpoint.summary(unvisited_rooms, false));
+        }
+        eprintln!(
+            "The crawl ran out of time after {}/{} rooms, writing the graph of the visited rooms.",
+            visited_rooms, rooms_to_crawl
+        );
+    }
+
+    checkpoint.link_room_relations();
+    assert!(matrixgraph::is_wellformed_graph(&checkpoint.graph, &Membership::ALL));
+    let dir = checkpoint.write(&joined_rooms, None);
+    if cut_short {
+        write_partial_marker(visited_rooms, rooms_to_crawl, &dir).expect("Could not mark partial graph");
+        return Ok(checkpoint.summary(unvisited_rooms, true));
+    }
+
+    // the completed crawl is the baseline for the next incremental crawl
+    store_crawl_state(&checkpoint, BASELINE_PATH).expect("Could not store crawl baseline");
+    if let Err(e) = fs::remove_file(CHECKPOINT_PATH) {
+        if e.kind() != io::ErrorKind::NotFound {
+            eprintln!("Could not remove crawl checkpoint: {}", e);
+        }
+    }
+
+    Ok(checkpoint.summary(0, true))
+}
+
+/// Updates the graph of the last crawl with the membership changes since then,
+/// and writes it together with the delta to the last crawl.
+/// Rooms with too many changes to be delivered by a single sync are crawled again completely.
+/// Only those and newly joined rooms get their activity sampled again.
+pub async fn crawl_incremental(
+    client: HttpsClient,
+    options: CrawlOptions,
+) -> Result<CrawlSummary, ruma_client::Error> {
+    let ignore = IgnoreFilter::new(&options.ignore_rules).expect("invalid ignore pattern");
+    let user_classifier =
+        UserClassifier::new(&options.user_classes).expect("invalid user class pattern");
+    let mut baseline = load_crawl_state(BASELINE_PATH).expect("Could not load crawl baseline");
+    if baseline.is_expired() {
+        eprintln!("The hash key of the last crawl expired, doing a full crawl with a new key.");
+        return crawl(client, options).await;
+    }
+    // users that are ignored now can't be found in the pseudonymized graph of the baseline
+    if baseline.ignore_rules != options.ignore_rules {
+        eprintln!("The ignore rules changed since the last crawl, doing a full crawl.");
+        return crawl(client, options).await;
+    }
+    let since = baseline.next_batch.clone().expect("crawl baseline has no sync token");
+    let previous_graph = baseline.graph.clone();
+
+    let response = sync_membership_changes(client.clone(), since).await?;
+    let joined_rooms =
+        crawlable_rooms(client.clone(), response.rooms.leave.keys().cloned().collect(), &ignore)
+            .await?;
+
+    // rooms the bot was kicked from or has left since the last crawl
+    let joined_hashes: HashSet<u64> = joined_rooms
+        .iter()
+        .map(|room| hash(&baseline.hash_key, room))
+        .collect();
+    let gone_rooms: HashSet<u64> = baseline
+        .visited_rooms
+        .iter()
+        .filter(|room_hash| !joined_hashes.contains(*room_hash))
+        .cloned()
+        .collect();
+    baseline.remove_rooms(&gone_rooms);
+
+    pacing::LOCAL.set_min_interval(options.request_interval);
+    let mut recrawled_rooms = 0;
+    let mut membership_changes_count = 0;
+    for room in &joined_rooms {
+        let joined_room = response.rooms.join.get(room);
+        let has_changes = joined_room.is_some();
+        let is_limited =
+            joined_room.map_or(false, |joined_room| joined_room.timeline.limited.unwrap_or(false));
+        // a room whose members were all ignored so far has no node yet
+        if !baseline.is_visited(room) || is_limited || (has_changes && !baseline.contains_room(room)) {
+            let (members, state) = crawl_room(
+                client.clone(),
+                room.clone(),
+                response.next_batch.clone(),
+                options.activity_samples,
+            )
+            .await?;
+            let mut room_hash = HashSet::new();
+            room_hash.insert(hash(&baseline.hash_key, room));
+            baseline.remove_rooms(&room_hash);
+            baseline.add_room(
+                room,
+                members,
+                state,
+                &ignore,
+                &user_classifier,
+            );
+            recrawled_rooms += 1;
+        } else if let Some(joined_room) = joined_room {
+            for (member, membership) in membership_changes(joined_room) {
+                if baseline.update_membership(
+                    room,
+                    &member,
+                    membership,
+                    &ignore,
+                    &user_classifier,
+                ) {
+                    membership_changes_count += 1;
+                }
+            }
+        }
+    }
+    eprintln!(
+        "Applied {} membership changes, crawled {} rooms completely, removed {} rooms.",
+        membership_changes_count,
+        recrawled_rooms,
+        gone_rooms.len()
+    );
+
+    baseline.link_room_relations();
+    assert!(matrixgraph::is_wellformed_graph(&baseline.graph, &Membership::ALL));
+    let delta = matrixgraph::graph_delta(&previous_graph, &baseline.graph);
+    baseline.write(&joined_rooms, Some(delta));
+
+    baseline.next_batch = Some(response.next_batch);
+    store_crawl_state(&baseline, BASELINE_PATH).expect("Could not store crawl baseline");
+
+    Ok(baseline.summary(0, true))
+}
+
+static FAREWELLS_PATH: &str = "farewells.ron";
+static PROJECT_PAGE: &str = "https://dsn-traveller.dsn.scc.kit.edu/";
+
+/// Whether the farewell notice reached a room before the bot left it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Farewell {
+    pub room_id: RoomId,
+    pub sent_at: time::SystemTime,
+    pub delivered: bool,
+    pub error: Option<String>,
+}
+
+pub fn load_farewells() -> Vec<Farewell> {
+    match fs::File::open(FAREWELLS_PATH) {
+        Ok(file) => {
+            let reader = io::BufReader::new(file);
+            ron::de::from_reader(reader).expect("Could not deserialize farewells.ron")
+        },
+        Err(_) => Vec::new(),
+    }
+}
+
+fn store_farewells(farewells: &[Farewell]) -> Result<(), io::Error> {
+    let file = fs::File::create(FAREWELLS_PATH)?;
+    let mut buffer = io::BufWriter::new(file);
+    write!(
+        &mut buffer,
+        "{}",
+        ron::ser::to_string_pretty(&farewells, ron::ser::PrettyConfig::default()).unwrap()
+    )
+}
+
+#[derive(Default)]
+pub struct ExitOptions {
+    /// only print the rooms that would be left to stdout, without leaving them
+    pub dry_run: bool,
+    /// posted in every room before leaving it, followed by a link to the project page,
+    /// so that the room moderators learn why the bot was there
+    pub farewell: Option<String>,
+}
+
+/// Posts the farewell notice in the room and records whether it was delivered.
+/// The record is kept for reference only, a room the bot joined again gets the notice on every exit.
+async fn bid_farewell(
+    client: HttpsClient,
+    room_id: &RoomId,
+    farewell: &str,
+    farewells: &mut Vec<Farewell>,
+) {
+    let message = format!("{} {}", farewell, PROJECT_PAGE);
+    let result = send_text(&pacing::MESSAGES, client, room_id.clone(), message).await;
+    if let Err(e) = &result {
+        eprintln!("Could not bid farewell in room {}: {:?}", room_id, e);
+    }
+    farewells.retain(|sent| &sent.room_id != room_id);
+    farewells.push(Farewell {
+        room_id: room_id.clone(),
+        sent_at: time::SystemTime::now(),
+        delivered: result.is_ok(),
+        error: result.err().map(|e| format!("{:?}", e)),
+    });
+    store_farewells(farewells).expect("Could not store farewells.ron");
+}
+
+/// Leaves and forgets the rooms, after the farewell notice if there is one.
+/// A dry run only prints the rooms it would leave to stdout. Returns the number of left rooms.
+async fn depart(
+    client: HttpsClient,
+    rooms: Vec<RoomId>,
+    options: &ExitOptions,
+) -> usize {
+    let room_count = rooms.len();
+    let mut left_count = 0;
+    let mut farewells = load_farewells();
+
+    // leaving as well as forgetting so that the server could part the federation for that rooms.
+    // Also, if we would not forget leaved rooms, they would appear as rooms where the bot has been
     // kicked from on a later join run.
     // TODO: is leave_and_forget_room enough so that the server can be shut down
     // without being a dead member of the federation?
-    match leave_and_forget_room(client.clone(), room_id.clone()).await {
-        Ok(_) => {
-            eprintln!("Left room: {}", room_id);
-            Ok(())
+    for room_id in rooms {
+        if options.dry_run {
+            left_count += 1;
+            println!("leave {}", room_id);
+            continue;
+        }
+        if let Some(farewell) = &options.farewell {
+            bid_farewell(client.clone(), &room_id, farewell, &mut farewells).await;
+        }
+        match leave_and_forget_room(client.clone(), room_id.clone()).await {
+            Ok(_) => {
+                left_count += 1;
+                eprintln!("Left room: {} ({}/{})", room_id, left_count, room_count);
+            },
+            Err(e) => eprintln!("Error leaving / forgetting room {}: {:?}", room_id, e),
+        }
+    }
+    left_count
+}
+
+/// Leaves and forgets all joined rooms except the control room.
+pub async fn exit_all(
+    client: HttpsClient,
+    control_room: RoomId,
+    options: &ExitOptions,
+) -> Result<(usize, usize), ruma_client::Error> {
+    let rooms: Vec<RoomId> = joined_rooms(client.clone())
+        .await?
+        .into_iter()
+        .filter(|room_id| room_id != &control_room)
+        .collect();
+    let joined_count = rooms.len();
+    let left_count = depart(client.clone(), rooms, options).await;
+    Ok((left_count, joined_count))
+}
+
+/// Rooms to leave, as given to exit.
+pub enum ExitTarget {
+    /// the room with the given id or alias, aliases that no longer resolve are looked up in the inventory
+    Room(RoomIdOrAliasId),
+    /// the rooms with a known alias matching the pattern
+    AliasPattern(regex::Regex),
+    /// the rooms whose members are all on the given server, apart from the bot itself
+    Server(String),
+}
+
+/// the joined rooms the target refers to
+async fn exit_target_rooms(
+    client: HttpsClient,
+    target: &ExitTarget,
+    joined_rooms: &[RoomId],
+    inventory: &Inventory,
+) -> Result<Vec<RoomId>, ruma_client::Error> {
+    match target {
+        ExitTarget::Room(room) => {
+            let room_id = match lookup_room_id(client.clone(), room.clone()).await {
+                Ok(room_id) => room_id,
+                Err(e) => {
+                    eprintln!("Could not look up room {}, skipping it: {:?}", room, e);
+                    return Ok(Vec::new());
+                },
+            };
+            if joined_rooms.contains(&room_id) {
+                Ok(vec![room_id])
+            } else {
+                eprintln!("not a member of room {}, nothing to leave.", room);
+                Ok(Vec::new())
+            }
         },
-        Err(e) => {
+        ExitTarget::AliasPattern(pattern) => Ok(joined_rooms
+            .iter()
+            .filter(|room_id| {
+                inventory
+                    .aliases(room_id)
+                    .iter()
+                    .any(|alias| pattern.is_match(&alias.to_string()))
+            })
+            .cloned()
+            .collect()),
+        ExitTarget::Server(server) => {
+            let own_user_id = client.session().expect("not logged in").user_id.to_string();
+            let mut rooms = Vec::new();
+            for room_id in joined_rooms {
+                let members = match room_members(client.clone(), room_id.clone()).await {
+                    Ok(members) => members,
+                    Err(e) => {
+                        eprintln!("Could not read members of room {}, skipping it: {:?}", room_id, e);
+                        continue;
+                    },
+                };
+                let mut joined_members = members
+                    .iter()
+                    .filter(|(user_id, membership)| {
+                        *membership == Membership::Join && user_id != &own_user_id
+                    })
+                    .peekable();
+                // rooms the bot is alone in are left to the other targets
+                if joined_members.peek().is_some()
+                    && joined_members.all(|(user_id, _)| &server_name(user_id) == server)
+                {
+                    rooms.push(room_id.clone());
+                }
+            }
+            Ok(rooms)
+        },
+    }
+}
+
+/// Leaves and forgets the joined rooms the targets refer to, but never the control room.
+/// Returns the number of left rooms and the number of targeted rooms.
+pub async fn exit_rooms(
+    client: HttpsClient,
+    control_room: RoomId,
+    targets: &[ExitTarget],
+    options: &ExitOptions,
+) -> Result<(usize, usize), ruma_client::Error> {
+    let joined_rooms = joined_rooms(client.clone()).await?;
+    let inventory = load_inventory();
+    let mut rooms: Vec<RoomId> = Vec::new();
+    for target in targets {
+        for room_id in exit_target_rooms(client.clone(), target, &joined_rooms, &inventory).await? {
+            if room_id != control_room && !rooms.contains(&room_id) {
+                rooms.push(room_id);
+            }
+        }
+    }
+
+    let room_count = rooms.len();
+    let left_count = depart(client.clone(), rooms, options).await;
+    Ok((left_count, room_count))
+}
+
+/// What became of a joined room that was replaced by a room upgrade.
+#[derive(Clone, Debug)]
+pub enum UpgradeOutcome {
+    /// the replacement room was joined
+    Followed,
+    /// the bot already was in the replacement room
+    AlreadyJoined,
+    /// the replacement room matched the ignore rules
+    Ignored,
+    /// the bot was kicked from or banned in the replacement room
+    Kicked,
+    /// joining the replacement room failed, the old room is kept to try again later
+    Failed(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct RoomUpgrade {
+    pub old_room: RoomId,
+    pub replacement: RoomId,
+    pub outcome: UpgradeOutcome,
+}
+
+/// Looks for tombstones in all joined rooms, joins their replacement rooms unless they are ignored
+/// or the bot was kicked from them, and leaves and forgets the old rooms.
+/// The control room is only reported, as the configuration has to be changed to move it.
+pub async fn follow_upgrades(
+    client: HttpsClient,
+    control_room: RoomId,
+    ignore_rules: &IgnoreRules,
+) -> Result<Vec<RoomUpgrade>, ruma_client::Error> {
+    let ignore = IgnoreFilter::new(ignore_rules).expect("invalid ignore pattern");
+    eprintln!("Syncing…");
+    let (rooms, _) = sync_rooms(client.clone()).await.expect("error syncing");
+    // records the kicks and bans since the last join or crawl
+    let kicked_rooms: HashSet<RoomId> =
+        honor_kicks(client.clone(), rooms.leave.keys().cloned().collect(), false)
+            .await?
+            .into_iter()
+            .map(|kick| kick.room_id)
+            .collect();
+    let joined_rooms: Vec<RoomId> = rooms.join.keys().cloned().collect();
+
+    let mut upgrades = Vec::new();
+    for room_id in &joined_rooms {
+        let tombstone = room_state_content(
+            client.clone(),
+            room_id.clone(),
+            EventType::RoomTombstone,
+            String::new(),
+        )
+        .await;
+        let tombstone = match tombstone {
+            Ok(tombstone) => tombstone,
+            Err(e) => {
+                eprintln!("Could not read the tombstone of room {}: {:?}", room_id, e);
+                continue;
+            },
+        };
+        let replacement = match state_room_id(&tombstone, "/replacement_room") {
+            Some(replacement) => replacement,
+            None => continue,
+        };
+        if room_id == &control_room {
+            eprintln!(
+                "The control room {} was replaced by {}, please update config.ron.",
+                room_id, replacement
+            );
+            continue;
+        }
+
+        let outcome = if joined_rooms.contains(&replacement) {
+            UpgradeOutcome::AlreadyJoined
+        } else if ignore.ignores_room(&replacement) {
+            UpgradeOutcome::Ignored
+        } else if kicked_rooms.contains(&replacement) {
+            UpgradeOutcome::Kicked
+        } else {
+            // the replacement is created by someone in the old room, most likely on the server of its id
+            let via = vec![server_name(&replacement)];
+            match join_room(client.clone(), RoomIdOrAliasId::RoomId(replacement.clone()), &via).await {
+                Ok(_) => UpgradeOutcome::Followed,
+                Err(e) => UpgradeOutcome::Failed(format!("{:?}", e)),
+            }
+        };
+        eprintln!("Room {} was replaced by {}: {:?}", room_id, replacement, outcome);
+
+        if let UpgradeOutcome::Failed(error) = &outcome {
+            eprintln!("Staying in room {} to try joining {} again later: {}", room_id, replacement, error);
+        } else if let Err(e) = leave_and_forget_room(client.clone(), room_id.clone()).await {
             eprintln!("Error leaving / forgetting room {}: {:?}", room_id, e);
-            Err(e)
+        }
+        upgrades.push(RoomUpgrade {
+            old_room: room_id.clone(),
+            replacement,
+            outcome,
+        });
+    }
+    Ok(upgrades)
+}
+
+static TRIP_PATH: &str = "trip.ron";
+// the join phase alone takes days for a few thousand rooms
+static TRIP_MAX_STAY: time::Duration = time::Duration::from_secs(7 * 24 * 60 * 60);
+// the join phase ends early enough to leave this share of the maximum stay to the crawl
+const TRIP_CRAWL_SHARE: u32 = 4;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum TripPhase {
+    /// collect the rooms of the public room directories
+    Discover,
+    /// join the discovered rooms
+    Join,
+    /// crawl the joined rooms and write the anonymized graph
+    Crawl,
+    /// leave all joined rooms
+    Leave,
+    Done,
+}
+
+/// A trip through the federation: discover, join, crawl and leave without idling in between.
+/// The trip is stored in trip.ron after every phase, so that an interrupted trip continues where it stopped.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Trip {
+    pub phase: TripPhase,
+    /// maximum time the bot stays in the joined rooms, counted from the start of the join phase
+    pub max_stay: time::Duration,
+    pub started_at: time::SystemTime,
+    pub joined_at: Option<time::SystemTime>,
+    pub discovered: usize,
+    pub joined: usize,
+    pub invited: usize,
+    /// what the crawl found in the rooms it visited before the stay was over
+    pub crawled: Option<CrawlSummary>,
+    pub left: usize,
+}
+
+impl Trip {
+    fn new(max_stay: time::Duration) -> Self {
+        Trip {
+            phase: TripPhase::Discover,
+            max_stay,
+            started_at: time::SystemTime::now(),
+            joined_at: None,
+            discovered: 0,
+            joined: 0,
+            invited: 0,
+            crawled: None,
+            left: 0,
+        }
+    }
+
+    /// how long the bot may still stay in the joined rooms
+    fn remaining_stay(&self) -> time::Duration {
+        let stayed = self
+            .joined_at
+            .map_or(time::Duration::default(), |joined_at| joined_at.elapsed().unwrap_or_default());
+        self.max_stay.checked_sub(stayed).unwrap_or_default()
+    }
+
+    /// when the stay in the rooms is over, except for the reserved time
+    fn stay_deadline(&self, reserved: time::Duration) -> time::Instant {
+        time::Instant::now() + self.remaining_stay().checked_sub(reserved).unwrap_or_default()
+    }
+}
+
+fn load_trip() -> Option<Trip> {
+    let file = fs::File::open(TRIP_PATH).ok()?;
+    let reader = io::BufReader::new(file);
+    Some(ron::de::from_reader(reader).expect("Could not deserialize trip.ron"))
+}
+
+fn store_trip(trip: &Trip) -> Result<(), io::Error> {
+    let file = fs::File::create(TRIP_PATH)?;
+    let mut buffer = io::BufWriter::new(file);
+    write!(
+        &mut buffer,
+        "{}",
+        ron::ser::to_string_pretty(&trip, ron::ser::PrettyConfig::default()).unwrap()
+    )
+}
+
+pub struct TripOptions {
+    /// maximum time the bot stays in the joined rooms, only used when a new trip starts
+    pub max_stay: time::Duration,
+    pub join: JoinOptions,
+    pub crawl: CrawlOptions,
+    pub exit: ExitOptions,
+}
+
+impl Default for TripOptions {
+    fn default() -> Self {
+        TripOptions {
+            max_stay: TRIP_MAX_STAY,
+            join: JoinOptions::default(),
+            crawl: CrawlOptions::default(),
+            exit: ExitOptions::default(),
+        }
+    }
+}
+
+/// Discovers, joins, crawls and leaves all rooms except the control room in one go,
+/// continuing an interrupted trip from trip.ron. The join queue and the crawl checkpoint
+/// of an interrupted phase are resumed. The join phase stops early enough to leave a share
+/// of the maximum stay to the crawl, which writes the graph of the rooms visited so far
+/// when the maximum stay is over.
+pub async fn trip(
+    client: HttpsClient,
+    control_room: RoomId,
+    options: TripOptions,
+) -> Result<Trip, ruma_client::Error> {
+    let mut trip = match load_trip() {
+        Some(trip) if trip.phase != TripPhase::Done => {
+            eprintln!("Continuing the trip in phase {:?}.", trip.phase);
+            trip
         },
+        _ => Trip::new(options.max_stay),
+    };
+    let interrupted_phase = trip.phase;
+    store_trip(&trip).expect("Could not store trip");
+
+    if trip.phase == TripPhase::Discover {
+        trip.discovered = discover_rooms(client.clone(), &options.join.ignore_rules).await?.len();
+        trip.phase = TripPhase::Join;
+        store_trip(&trip).expect("Could not store trip");
+    }
+
+    if trip.phase == TripPhase::Join {
+        if trip.joined_at.is_none() {
+            trip.joined_at = Some(time::SystemTime::now());
+            store_trip(&trip).expect("Could not store trip");
+        }
+        let joined_rooms = HashSet::from_iter(joined_rooms(client.clone()).await?);
+        let rooms = discovered_join_targets(&load_discovery(), &joined_rooms);
+        let join_options = JoinOptions {
+            resume: interrupted_phase == TripPhase::Join,
+            deadline: Some(trip.stay_deadline(trip.max_stay / TRIP_CRAWL_SHARE)),
+            ..options.join
+        };
+        let summary = join_rooms(client.clone(), rooms, join_options).await?;
+        write_join_reports(&summary.reports).expect("Could not write join reports");
+        trip.joined += summary.join_count;
+        trip.invited += summary.invite_count;
+        trip.phase = TripPhase::Crawl;
+        store_trip(&trip).expect("Could not store trip");
+    }
+
+    if trip.phase == TripPhase::Crawl {
+        let crawl_options = CrawlOptions {
+            resume: interrupted_phase == TripPhase::Crawl,
+            deadline: Some(trip.stay_deadline(time::Duration::default())),
+            ..options.crawl
+        };
+        trip.crawled = Some(crawl(client.clone(), crawl_options).await?);
+        trip.phase = TripPhase::Leave;
+        store_trip(&trip).expect("Could not store trip");
+    }
+
+    if trip.phase == TripPhase::Leave {
+        let (left_count, _) = exit_all(client.clone(), control_room, &options.exit).await?;
+        trip.left += left_count;
+        trip.phase = TripPhase::Done;
+        store_trip(&trip).expect("Could not store trip");
+    }
+    Ok(trip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(user_id: &str) -> UserId {
+        UserId::try_from(user_id).unwrap()
+    }
+
+    #[test]
+    fn invite_policy_allows_and_denies_servers() {
+        let policy = InvitePolicy::new(&InviteRules {
+            allow_servers: vec!["matrix.org".to_string(), "evilmatrix.org".to_string()],
+            deny_servers: vec![r"evilmatrix\.org".to_string()],
+            ..InviteRules::default()
+        })
+        .unwrap();
+        assert!(policy.rejection(Some(&user("@alice:matrix.org")), None).is_ok());
+        assert!(policy.rejection(Some(&user("@mallory:evilmatrix.org")), None).is_err());
+        assert!(policy.rejection(Some(&user("@bob:example.org")), None).is_err());
+        assert!(policy.rejection(None, None).is_err());
+    }
+
+    #[test]
+    fn invite_policy_denies_whole_server_names_only() {
+        let policy = InvitePolicy::new(&InviteRules {
+            deny_servers: vec![r"matrix\.org".to_string()],
+            ..InviteRules::default()
+        })
+        .unwrap();
+        assert!(policy.rejection(Some(&user("@alice:matrix.org")), None).is_err());
+        assert!(policy.rejection(Some(&user("@alice:evilmatrix.org")), None).is_ok());
+        assert!(policy.rejection(Some(&user("@alice:matrix.org.example")), None).is_ok());
+        assert!(policy.rejection(Some(&user("@alice:matrixXorg")), None).is_ok());
+    }
+
+    #[test]
+    fn invite_policy_denies_users() {
+        let policy = InvitePolicy::new(&InviteRules {
+            deny_users: vec![r"^@spam.*:example\.org$".to_string()],
+            ..InviteRules::default()
+        })
+        .unwrap();
+        assert!(policy.rejection(Some(&user("@spammer:example.org")), None).is_err());
+        assert!(policy.rejection(Some(&user("@alice:example.org")), None).is_ok());
+    }
+
+    #[test]
+    fn invite_policy_requires_known_member_count() {
+        let policy = InvitePolicy::new(&InviteRules {
+            min_members: 10,
+            ..InviteRules::default()
+        })
+        .unwrap();
+        let inviter = user("@alice:example.org");
+        assert!(policy.rejection(Some(&inviter), None).is_err());
+        assert!(policy.rejection(Some(&inviter), Some(10)).is_err());
+        assert!(policy.rejection(Some(&inviter), Some(11)).is_ok());
+    }
+
+    #[test]
+    fn invalid_invite_patterns_are_refused() {
+        let rules = InviteRules {
+            deny_servers: vec!["(".to_string()],
+            ..InviteRules::default()
+        };
+        assert!(InvitePolicy::new(&rules).is_err());
+    }
+
+    #[test]
+    fn csv_fields_are_quoted_if_needed() {
+        assert_eq!(csv_field("!room:example.org"), "!room:example.org");
+        assert_eq!(csv_field("#a,b:example.org"), "\"#a,b:example.org\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+        assert_eq!(csv_field(""), "");
+    }
+
+    fn queued(room: &str) -> QueuedRoom {
+        QueuedRoom::new(JoinTarget::new(RoomIdOrAliasId::try_from(room).unwrap()))
+    }
+
+    #[test]
+    fn join_order_interleaves_servers_and_skips_finished_rooms() {
+        let mut queue = vec![
+            queued("#one:a.org"),
+            queued("#two:a.org"),
+            queued("#three:a.org"),
+            queued("#four:b.org"),
+            queued("!five:c.org"),
+            queued("#six:b.org"),
+        ];
+        queue[2].state = JoinState::Joined;
+        assert_eq!(join_order(&queue), vec![0, 3, 4, 1, 5]);
+    }
+
+    #[test]
+    fn inventory_follows_moved_aliases() {
+        let room_a = RoomId::try_from("!a:example.org").unwrap();
+        let room_b = RoomId::try_from("!b:example.org").unwrap();
+        let alias = RoomAliasId::try_from("#room:example.org").unwrap();
+        let other_alias = RoomAliasId::try_from("#other:example.org").unwrap();
+
+        let mut inventory = Inventory::default();
+        inventory.add(room_a.clone(), alias.clone());
+        inventory.add(room_a.clone(), other_alias.clone());
+        assert_eq!(inventory.room_id(&alias), Some(&room_a));
+        assert_eq!(inventory.aliases(&room_a), &[alias.clone(), other_alias.clone()][..]);
+
+        inventory.add(room_b.clone(), alias.clone());
+        assert_eq!(inventory.room_id(&alias), Some(&room_b));
+        assert_eq!(inventory.aliases(&room_a), &[other_alias.clone()][..]);
+
+        inventory.add(room_b.clone(), other_alias.clone());
+        assert!(inventory.aliases(&room_a).is_empty());
+        assert_eq!(inventory.aliases(&room_b), &[alias, other_alias][..]);
     }
 }
@@ -1,14 +1,16 @@
+use std::collections::HashSet;
 use std::convert::TryFrom;
 use std::fs;
 use std::io;
 use std::io::prelude::*;
 use std::iter::FromIterator;
+use std::time;
 
-use clap::{crate_authors, crate_version, App, Arg, SubCommand};
+use clap::{crate_authors, crate_version, App, Arg, ArgMatches, SubCommand};
 
 use ruma_client::{
     HttpsClient, Session,
-    identifiers::{RoomAliasId, RoomId, RoomIdOrAliasId},
+    identifiers::{RoomId, RoomIdOrAliasId},
 };
 use url::Url;
 
@@ -19,6 +21,19 @@ use serde::{Deserialize, Serialize};
 struct TravellerConfig {
     homeserver_url: Url,
     control_room: RoomIdOrAliasId,
+    /// how the crawl classifies users, e.g. to recognize the appservice namespaces of bridges
+    #[serde(default)]
+    user_classes: dsn_traveller::UserClassRules,
+    /// rooms and users left out of joins and crawls, e.g. servers that opted out
+    #[serde(default)]
+    ignore_rules: dsn_traveller::IgnoreRules,
+    /// which invites are followed, the others are rejected
+    #[serde(default)]
+    invite_rules: dsn_traveller::InviteRules,
+    /// notice posted in every room before leaving it, followed by a link to the project page.
+    /// Rooms are left silently if there is none.
+    #[serde(default)]
+    farewell: Option<String>,
 }
 
 fn load_config() -> Result<TravellerConfig, io::Error> {
@@ -26,6 +41,15 @@ fn load_config() -> Result<TravellerConfig, io::Error> {
     let reader = io::BufReader::new(file);
     let config: TravellerConfig =
         ron::de::from_reader(reader).expect("Could not deserialize config.ron");
+    if let Err(e) = dsn_traveller::UserClassifier::new(&config.user_classes) {
+        panic!("invalid user class pattern in config.ron: {}", e);
+    }
+    if let Err(e) = dsn_traveller::IgnoreFilter::new(&config.ignore_rules) {
+        panic!("invalid ignore pattern in config.ron: {}", e);
+    }
+    if let Err(e) = dsn_traveller::InvitePolicy::new(&config.invite_rules) {
+        panic!("invalid invite pattern in config.ron: {}", e);
+    }
     Ok(config)
 }
 
@@ -76,6 +100,10 @@ fn get_config() -> TravellerConfig {
             let config = TravellerConfig {
                 homeserver_url,
                 control_room,
+                user_classes: dsn_traveller::UserClassRules::default(),
+                ignore_rules: dsn_traveller::IgnoreRules::default(),
+                invite_rules: dsn_traveller::InviteRules::default(),
+                farewell: None,
             };
             store_config(&config).unwrap();
             config
@@ -121,17 +149,37 @@ async fn get_client(
     Ok(client)
 }
 
-async fn join(room_list: Vec<String>) -> Result<(), ruma_client::Error> {
+async fn join(
+    room_list: Vec<String>,
+    via: Vec<String>,
+    options: dsn_traveller::JoinOptions,
+) -> Result<(), ruma_client::Error> {
     let config = get_config();
     let client = get_client(&config).await?;
+    let options = dsn_traveller::JoinOptions {
+        ignore_rules: config.ignore_rules.clone(),
+        invite_rules: config.invite_rules.clone(),
+        ..options
+    };
 
-    let room_aliases = Vec::from_iter(room_list.into_iter().map(|room| {
-        RoomAliasId::try_from(&room[..]).unwrap_or_else(|_| panic!("invalid room alias: {}", room))
+    let rooms = Vec::from_iter(room_list.into_iter().map(|room| dsn_traveller::JoinTarget {
+        room: RoomIdOrAliasId::try_from(&room[..])
+            .unwrap_or_else(|_| panic!("invalid room alias or id: {}", room)),
+        via: via.clone(),
     }));
 
-    let (join_count, invite_count, leave_count) =
-        dsn_traveller::join_rooms(client.clone(), room_aliases).await?;
+    let dry_run = options.dry_run;
+    let dsn_traveller::JoinSummary { join_count, invite_count, leave_count, reports } =
+        dsn_traveller::join_rooms(client.clone(), rooms, options).await?;
+    if dry_run {
+        eprintln!(
+            "would join {} rooms and follow {} invites.",
+            join_count, invite_count
+        );
+        return Ok(());
+    }
     eprintln!("finished joining rooms");
+    dsn_traveller::write_join_reports(&reports).expect("Could not write join reports");
 
     let message = format!("Good evening, Gentlemen! \
         Today I learned about {} new rooms, was invited to {} new rooms, and I'm not a member of {} rooms.",
@@ -153,17 +201,136 @@ async fn join(room_list: Vec<String>) -> Result<(), ruma_client::Error> {
     Ok(())
 }
 
-async fn crawl() -> Result<(), ruma_client::Error> {
+async fn join_space(
+    space: dsn_traveller::JoinTarget,
+    dry_run: bool,
+) -> Result<(), ruma_client::Error> {
+    let config = get_config();
+    let client = get_client(&config).await?;
+
+    let (join_count, room_count) = dsn_traveller::join_space(
+        client.clone(),
+        space.clone(),
+        &config.ignore_rules,
+        dry_run,
+    )
+    .await?;
+    if dry_run {
+        eprintln!("would join {} of the {} known rooms in the space.", join_count, room_count);
+        return Ok(());
+    }
+    eprintln!("finished joining space");
+
+    let message = format!("Good evening, Gentlemen! \
+        Today I explored the space {} and learned about {} new rooms of the {} rooms in it.",
+        space.room, join_count, room_count);
+
+    let control_room_id = dsn_traveller::into_room_id(
+        client.clone(),
+        config.control_room.clone()
+    )
+    .await.expect("Could not resolve control room alias");
+
+    dsn_traveller::send_message(
+        client.clone(),
+        control_room_id,
+        message.clone()
+    ).await?;
+    eprintln!("{}", message);
+
+    Ok(())
+}
+
+async fn discover() -> Result<(), ruma_client::Error> {
     let config = get_config();
     let client = get_client(&config).await?;
 
-    let (room_count, user_count, server_count) = dsn_traveller::crawl(client.clone()).await?;
+    let discovered = dsn_traveller::discover_rooms(client.clone(), &config.ignore_rules).await?;
+    let joined_rooms: HashSet<RoomId> =
+        HashSet::from_iter(dsn_traveller::joined_rooms(client.clone()).await?);
+    let rooms = dsn_traveller::discovered_join_targets(&discovered, &joined_rooms);
+    eprintln!(
+        "Discovered {} rooms, {} of them are not joined yet.",
+        discovered.len(),
+        rooms.len(),
+    );
+
+    let dsn_traveller::JoinSummary { join_count, invite_count, leave_count, reports } =
+        dsn_traveller::join_rooms(
+            client.clone(),
+            rooms,
+            dsn_traveller::JoinOptions {
+                // keeps the unfinished rooms of an interrupted join
+                resume: true,
+                ignore_rules: config.ignore_rules.clone(),
+                invite_rules: config.invite_rules.clone(),
+                ..dsn_traveller::JoinOptions::default()
+            },
+        )
+        .await?;
+    eprintln!("finished joining rooms");
+    dsn_traveller::write_join_reports(&reports).expect("Could not write join reports");
+
+    let message = format!("Good evening, Gentlemen! \
+        Today I browsed the room directories and found {} public rooms. \
+        I learned about {} new rooms, was invited to {} new rooms, and I'm not a member of {} rooms.",
+        discovered.len(), join_count, invite_count, leave_count);
+
+    let control_room_id = dsn_traveller::into_room_id(
+        client.clone(),
+        config.control_room.clone()
+    )
+    .await.expect("Could not resolve control room alias");
+
+    dsn_traveller::send_message(
+        client.clone(),
+        control_room_id,
+        message.clone()
+    ).await?;
+    eprintln!("{}", message);
+
+    Ok(())
+}
+
+async fn mine(depth: usize) -> Result<(), ruma_client::Error> {
+    let config = get_config();
+    let client = get_client(&config).await?;
+
+    let room_aliases = dsn_traveller::mine_aliases(client.clone(), depth).await?;
+    eprintln!("found {} rooms to join", room_aliases.len());
+    // to be piped into join --stdin
+    for alias in room_aliases {
+        println!("{}", alias);
+    }
+    Ok(())
+}
+
+async fn crawl(
+    options: dsn_traveller::CrawlOptions,
+    incremental: bool,
+) -> Result<(), ruma_client::Error> {
+    let config = get_config();
+    let client = get_client(&config).await?;
+    let options = dsn_traveller::CrawlOptions {
+        user_classes: config.user_classes.clone(),
+        ignore_rules: config.ignore_rules.clone(),
+        ..options
+    };
+
+    let summary = if incremental && dsn_traveller::has_crawl_baseline() {
+        dsn_traveller::crawl_incremental(client.clone(), options).await?
+    } else {
+        if incremental {
+            eprintln!("No previous crawl to continue from, doing a full crawl.");
+        }
+        dsn_traveller::crawl(client.clone(), options).await?
+    };
     eprintln!("queried room membership");
 
     let message = format!(
         "Good evening, Gentlemen! \
          On my travelling, I visited {} rooms on {} different servers, and saw {} people!",
-        room_count, server_count, user_count,
+        summary.rooms, summary.joined_servers, summary.joined_users,
     );
 
     let control_room_id = dsn_traveller::into_room_id(
@@ -182,7 +349,110 @@ async fn crawl() -> Result<(), ruma_client::Error> {
     Ok(())
 }
 
-async fn exit_all() -> Result<(), ruma_client::Error> {
+async fn trip(max_stay: Option<time::Duration>) -> Result<(), ruma_client::Error> {
+    let config = get_config();
+    let client = get_client(&config).await?;
+
+    let control_room_id = dsn_traveller::into_room_id(
+        client.clone(),
+        config.control_room.clone()
+    )
+    .await.expect("Could not resolve control room alias");
+
+    let defaults = dsn_traveller::TripOptions::default();
+    let options = dsn_traveller::TripOptions {
+        max_stay: max_stay.unwrap_or(defaults.max_stay),
+        join: dsn_traveller::JoinOptions {
+            ignore_rules: config.ignore_rules.clone(),
+            invite_rules: config.invite_rules.clone(),
+            ..defaults.join
+        },
+        crawl: dsn_traveller::CrawlOptions {
+            user_classes: config.user_classes.clone(),
+            ignore_rules: config.ignore_rules.clone(),
+            ..defaults.crawl
+        },
+        exit: dsn_traveller::ExitOptions {
+            dry_run: false,
+            farewell: config.farewell.clone(),
+        },
+    };
+    let trip = dsn_traveller::trip(client.clone(), control_room_id.clone(), options).await?;
+
+    let crawled = match trip.crawled {
+        Some(summary) if !summary.written => format!(
+            "ran out of time after crawling {} rooms, too few to keep, with {} rooms left",
+            summary.rooms, summary.unvisited_rooms
+        ),
+        Some(summary) if summary.unvisited_rooms > 0 => format!(
+            "crawled {} rooms with {} joined users on {} servers before running out of time, with {} rooms left",
+            summary.rooms, summary.joined_users, summary.joined_servers, summary.unvisited_rooms
+        ),
+        Some(summary) => format!(
+            "crawled {} rooms with {} joined users on {} servers",
+            summary.rooms, summary.joined_users, summary.joined_servers
+        ),
+        None => "ran out of time before the crawl was done".to_string(),
+    };
+    let message = format!("Good bye, Gentlemen! \
+        I'm back from my trip, on which I found {} public rooms, learned about {} new rooms, \
+        was invited to {} new rooms, {}, and departed from {} rooms.",
+        trip.discovered, trip.joined, trip.invited, crawled, trip.left);
+
+    dsn_traveller::send_message(
+        client.clone(),
+        control_room_id,
+        message.clone()
+    ).await?;
+    eprintln!("{}", message);
+
+    Ok(())
+}
+
+async fn upgrade() -> Result<(), ruma_client::Error> {
+    let config = get_config();
+    let client = get_client(&config).await?;
+
+    let control_room_id = dsn_traveller::into_room_id(
+        client.clone(),
+        config.control_room.clone()
+    )
+    .await.expect("Could not resolve control room alias");
+
+    let upgrades = dsn_traveller::follow_upgrades(
+        client.clone(),
+        control_room_id.clone(),
+        &config.ignore_rules,
+    )
+    .await?;
+    eprintln!("finished following room upgrades");
+
+    let mut message = format!(
+        "Good evening, Gentlemen! Today I found {} rooms that were replaced by a room upgrade.",
+        upgrades.len()
+    );
+    for upgrade in &upgrades {
+        let outcome = match &upgrade.outcome {
+            dsn_traveller::UpgradeOutcome::Followed => "moved over".to_string(),
+            dsn_traveller::UpgradeOutcome::AlreadyJoined => "was already there".to_string(),
+            dsn_traveller::UpgradeOutcome::Ignored => "left, the replacement is ignored".to_string(),
+            dsn_traveller::UpgradeOutcome::Kicked => "left, I was kicked from the replacement".to_string(),
+            dsn_traveller::UpgradeOutcome::Failed(error) => format!("stayed, joining failed: {}", error),
+        };
+        message.push_str(&format!("\n{} → {}: {}", upgrade.old_room, upgrade.replacement, outcome));
+    }
+
+    dsn_traveller::send_message(
+        client.clone(),
+        control_room_id,
+        message.clone()
+    ).await?;
+    eprintln!("{}", message);
+
+    Ok(())
+}
+
+async fn exit_all(dry_run: bool) -> Result<(), ruma_client::Error> {
     let config = get_config();
 
     let client = get_client(&config).await?;
@@ -195,8 +465,16 @@ async fn exit_all() -> Result<(), ruma_client::Error> {
 
     let (left_count, joined_count) = dsn_traveller::exit_all(
         client.clone(),
-        control_room_id.clone()
+        control_room_id.clone(),
+        &dsn_traveller::ExitOptions {
+            dry_run,
+            farewell: config.farewell.clone(),
+        },
     ).await?;
+    if dry_run {
+        eprintln!("would depart from {} of the {} rooms I visited.", left_count, joined_count);
+        return Ok(());
+    }
 
     let message = format!(
         "Good bye, Gentlemen! \
@@ -214,7 +492,10 @@ async fn exit_all() -> Result<(), ruma_client::Error> {
     Ok(())
 }
 
-async fn exit(room_id: RoomId) -> Result<(), ruma_client::Error> {
+async fn exit(
+    targets: Vec<dsn_traveller::ExitTarget>,
+    dry_run: bool,
+) -> Result<(), ruma_client::Error> {
     let config = get_config();
     let client = get_client(&config).await?;
 
@@ -224,16 +505,21 @@ async fn exit(room_id: RoomId) -> Result<(), ruma_client::Error> {
     )
     .await.expect("Could not resolve control room alias");
 
-    let message = match dsn_traveller::exit(client.clone(), room_id.clone()).await {
-        Ok(_) => format!(
-            "Good bye, Gentlemen! Today, I successfully departed from room {}.",
-            room_id
-        ),
-        Err(e) => format!(
-            "Gentlemen, there was a hitch with leaving from room {}! {:?}",
-            room_id, e
-        ),
+    let options = dsn_traveller::ExitOptions {
+        dry_run,
+        farewell: config.farewell.clone(),
     };
+    let (left_count, room_count) =
+        dsn_traveller::exit_rooms(client.clone(), control_room_id.clone(), &targets, &options).await?;
+    if dry_run {
+        eprintln!("would depart from {} rooms.", left_count);
+        return Ok(());
+    }
+
+    let message = format!(
+        "Good bye, Gentlemen! Today, I departed from {} of the {} rooms I was asked to leave.",
+        left_count, room_count
+    );
 
     dsn_traveller::send_message(
         client.clone(),
@@ -245,6 +531,47 @@ async fn exit(room_id: RoomId) -> Result<(), ruma_client::Error> {
     Ok(())
 }
 
+fn exit_targets(matches: &ArgMatches) -> Vec<dsn_traveller::ExitTarget> {
+    let mut rooms: Vec<String> = match matches.values_of("rooms") {
+        Some(rooms) => Vec::from_iter(rooms.map(|s| s.to_string())),
+        None => Vec::new(),
+    };
+    if let Some(path) = matches.value_of("list") {
+        let file = fs::File::open(path).expect("Unable to open given room list");
+        rooms.extend(
+            io::BufReader::new(file)
+                .lines()
+                .map(|line| line.unwrap().trim().to_string())
+                .filter(|line| !line.is_empty() && !line.starts_with('#')),
+        );
+    }
+
+    let mut targets = Vec::from_iter(rooms.into_iter().map(|room| {
+        dsn_traveller::ExitTarget::Room(
+            RoomIdOrAliasId::try_from(&room[..])
+                .unwrap_or_else(|_| panic!("invalid room alias or id: {}", room)),
+        )
+    }));
+    if let Some(patterns) = matches.values_of("pattern") {
+        targets.extend(patterns.map(|pattern| {
+            dsn_traveller::ExitTarget::AliasPattern(
+                regex::Regex::new(pattern).expect("Unable to parse given alias pattern"),
+            )
+        }));
+    }
+    if let Some(servers) = matches.values_of("server") {
+        targets.extend(servers.map(|server| dsn_traveller::ExitTarget::Server(server.to_string())));
+    }
+    targets
+}
+
+fn via_servers(matches: &ArgMatches) -> Vec<String> {
+    match matches.values_of("via") {
+        Some(servers) => Vec::from_iter(servers.map(|s| s.to_string())),
+        None => Vec::new(),
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), ruma_client::Error> {
     let matches = App::new("DSN Traveller")
@@ -255,28 +582,123 @@ async fn main() -> Result<(), ruma_client::Error> {
                     .about("join the given rooms")
                     .display_order(1)
                     .arg(Arg::with_name("stdin")
-                         .help("read room aliases from stdin instead of positional arguments, one alias per line")
+                         .help("read room aliases or ids from stdin instead of positional arguments, one per line")
                          .long("stdin")
-                         .conflicts_with("rooms"))
+                         .conflicts_with_all(&["room_aliases", "space"]))
                     .arg(Arg::with_name("room_aliases")
-                         .help("room aliases to join")
-                         .conflicts_with("stdin")
+                         .help("room aliases or ids to join")
+                         .conflicts_with_all(&["stdin", "space"])
                          .multiple(true))
+                    .arg(Arg::with_name("space")
+                         .help("join the given space and all rooms in it")
+                         .long("space")
+                         .takes_value(true)
+                         .conflicts_with_all(&["stdin", "room_aliases", "resume"]))
+                    .arg(Arg::with_name("via")
+                         .help("server to join the given rooms or space through, can be given multiple times. Needed to join rooms by id that the homeserver doesn't know yet")
+                         .long("via")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1))
+                    .arg(Arg::with_name("resume")
+                         .help("continue the join queue of a previous join, adding the given rooms to it")
+                         .long("resume"))
+                    .arg(Arg::with_name("server_interval")
+                         .help("minimum seconds between two joins of rooms of the same server")
+                         .long("server-interval")
+                         .takes_value(true)
+                         .conflicts_with("space"))
+                    .arg(Arg::with_name("dry_run")
+                         .help("only print the rooms that would be joined and the invites that would be followed or rejected, without joining, rejecting or messaging")
+                         .long("dry-run"))
                    )
-        .subcommand(SubCommand::with_name("crawl")
+        .subcommand(SubCommand::with_name("discover")
                     .display_order(2)
+                    .about("join the rooms listed in the public room directories of all known servers, keeping the unfinished rooms of the join queue")
+                   )
+        .subcommand(SubCommand::with_name("mine")
+                    .display_order(3)
+                    .about("print aliases of rooms not joined yet which are mentioned in the joined rooms, one alias per line")
+                    .arg(Arg::with_name("depth")
+                         .help("how many hops to follow mentions, further hops only work for world-readable rooms")
+                         .long("depth")
+                         .takes_value(true)
+                         .default_value("1"))
+                   )
+        .subcommand(SubCommand::with_name("crawl")
+                    .display_order(4)
                     .about("visit all joined rooms and store the network graph")
+                    .arg(Arg::with_name("resume")
+                         .help("continue an interrupted crawl from its checkpoint")
+                         .long("resume")
+                         .conflicts_with("incremental"))
+                    .arg(Arg::with_name("incremental")
+                         .help("only apply the membership changes since the last crawl, falls back to a full crawl if there is none")
+                         .long("incremental")
+                         .conflicts_with("resume"))
+                    .arg(Arg::with_name("concurrency")
+                         .help("number of membership requests in flight at the same time")
+                         .long("concurrency")
+                         .takes_value(true))
+                    .arg(Arg::with_name("interval")
+                         .help("minimum milliseconds between two requests to the homeserver, shared by all in-flight requests. The crawl slows down while the homeserver rate limits it")
+                         .long("interval")
+                         .takes_value(true))
+                    .arg(Arg::with_name("activity_samples")
+                         .help("maximum number of recent events sampled per room to measure its activity, 0 disables sampling")
+                         .long("activity-samples")
+                         .takes_value(true))
+                   )
+        .subcommand(SubCommand::with_name("trip")
+                    .display_order(5)
+                    .about("discover, join, crawl and leave all rooms in one go, continuing an interrupted trip")
+                    .arg(Arg::with_name("max_stay")
+                         .help("maximum hours to stay in the joined rooms, counted from the first join. Applies to new trips only")
+                         .long("max-stay")
+                         .takes_value(true))
+                   )
+        .subcommand(SubCommand::with_name("upgrade")
+                    .display_order(6)
+                    .about("follow room upgrades: join the replacements of tombstoned rooms and leave the old rooms")
                    )
         .subcommand(SubCommand::with_name("exit")
-                    .display_order(3)
-                    .about("leave and forget given room id, or all previously-joined rooms if no id is given")
-                    .arg(Arg::with_name("room_id")
-                         .help("room id to leave & forget"))
+                    .display_order(7)
+                    .about("leave and forget the given rooms, or all previously-joined rooms if no rooms are given")
+                    .arg(Arg::with_name("rooms")
+                         .help("room ids or aliases to leave & forget, aliases that no longer resolve are looked up in the inventory of known aliases")
+                         .multiple(true))
+                    .arg(Arg::with_name("list")
+                         .help("file with room ids or aliases to leave, one per line. Lines starting with # are skipped")
+                         .long("list")
+                         .takes_value(true))
+                    .arg(Arg::with_name("pattern")
+                         .help("regular expression, leave the rooms with a known alias matching it. Can be given multiple times")
+                         .long("pattern")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1))
+                    .arg(Arg::with_name("server")
+                         .help("server name, leave the rooms whose members are all on that server. Can be given multiple times")
+                         .long("server")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1))
+                    .arg(Arg::with_name("dry_run")
+                         .help("only print the rooms that would be left, without leaving or messaging")
+                         .long("dry-run"))
                    )
         .get_matches();
 
     match matches.subcommand() {
         // ("join", Some(_)) => {
+        ("join", Some(join_matches)) if join_matches.is_present("space") => {
+            let space = join_matches.value_of("space").unwrap();
+            let space = RoomIdOrAliasId::try_from(space).expect("Unable to parse given space");
+            join_space(
+                dsn_traveller::JoinTarget { room: space, via: via_servers(join_matches) },
+                join_matches.is_present("dry_run"),
+            ).await
+        },
         ("join", Some(join_matches)) => {
             let room_list: Vec<String> = {
                 if join_matches.is_present("stdin") {
@@ -291,24 +713,72 @@ async fn main() -> Result<(), ruma_client::Error> {
                 }
             };
 
-            join(room_list).await
+            let defaults = dsn_traveller::JoinOptions::default();
+            let options = dsn_traveller::JoinOptions {
+                resume: join_matches.is_present("resume"),
+                server_interval: match join_matches.value_of("server_interval") {
+                    Some(interval) => time::Duration::from_secs(
+                        interval.parse().expect("Unable to parse given server interval"),
+                    ),
+                    None => defaults.server_interval,
+                },
+                ignore_rules: defaults.ignore_rules,
+                invite_rules: defaults.invite_rules,
+                dry_run: join_matches.is_present("dry_run"),
+                deadline: defaults.deadline,
+            };
+            join(room_list, via_servers(join_matches), options).await
         },
-        ("crawl", Some(_)) => crawl().await,
-        ("exit", Some(exit_matches)) => {
-            let room_id = {
-                if exit_matches.is_present("room_id") {
-                    let room_id = exit_matches.value_of("room_id").unwrap();
-                    let room_id =
-                        RoomId::try_from(room_id).expect("Unable to parse given RoomId");
-                    Some(room_id)
-                } else {
-                    None
-                }
+        ("discover", Some(_)) => discover().await,
+        ("mine", Some(mine_matches)) => {
+            let depth = mine_matches.value_of("depth").unwrap();
+            mine(depth.parse().expect("Unable to parse given depth")).await
+        },
+        ("crawl", Some(crawl_matches)) => {
+            let defaults = dsn_traveller::CrawlOptions::default();
+            let options = dsn_traveller::CrawlOptions {
+                resume: crawl_matches.is_present("resume"),
+                concurrency: match crawl_matches.value_of("concurrency") {
+                    Some(concurrency) => {
+                        concurrency.parse().expect("Unable to parse given concurrency")
+                    },
+                    None => defaults.concurrency,
+                },
+                request_interval: match crawl_matches.value_of("interval") {
+                    Some(interval) => time::Duration::from_millis(
+                        interval.parse().expect("Unable to parse given interval"),
+                    ),
+                    None => defaults.request_interval,
+                },
+                user_classes: defaults.user_classes,
+                ignore_rules: defaults.ignore_rules,
+                activity_samples: match crawl_matches.value_of("activity_samples") {
+                    Some(samples) => samples.parse().expect("Unable to parse given activity samples"),
+                    None => defaults.activity_samples,
+                },
+                deadline: defaults.deadline,
             };
-            if let Some(room_id) = room_id {
-                exit(room_id).await
+            crawl(options, crawl_matches.is_present("incremental")).await
+        },
+        ("trip", Some(trip_matches)) => {
+            let max_stay = trip_matches.value_of("max_stay").map(|hours| {
+                time::Duration::from_secs(
+                    hours.parse::<u64>().expect("Unable to parse given max stay") * 60 * 60,
+                )
+            });
+            trip(max_stay).await
+        },
+        ("upgrade", Some(_)) => upgrade().await,
+        ("exit", Some(exit_matches)) => {
+            let dry_run = exit_matches.is_present("dry_run");
+            // an empty room list must not end up leaving all rooms
+            let targeted = ["rooms", "list", "pattern", "server"]
+                .iter()
+                .any(|arg| exit_matches.is_present(arg));
+            if targeted {
+                exit(exit_targets(exit_matches), dry_run).await
             } else {
-                exit_all().await
+                exit_all(dry_run).await
             }
         },
         ("", None) => {
@@ -0,0 +1,214 @@
+use std::cmp;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time;
+
+use futures_timer::Delay;
+use lazy_static::lazy_static;
+
+// try best to avoid rate limiting for federation requests for resolve_alias and join_room
+// 2500 rooms * 2s = 1.5 Days
+// 2500 rooms * 5s = 3 Days
+// more info on rate limiting:
+// https://github.com/matrix-org/synapse/blob/9bba6ebaa903a81cd94fada114aa71e20b685adb/synapse/config/ratelimiting.py#L30
+// in case of room_crawl, it's only my own home server rate limiting,
+// as this does not require federation requests, I should be able to raise that limit arbitrarily
+// 2500 rooms * 0.2 = 8 minutes
+// 5 seconds resulted in load factor of 4, spacing out to have more time for the computation
+static ROOM_JOIN_DELAY: time::Duration = time::Duration::from_millis(64000);
+static ROOM_CRAWL_DELAY: time::Duration = time::Duration::from_millis(500);
+// The load caused by joins doesn't show up as rate limiting,
+// so joins never get faster than half the hand-tuned delay.
+static FEDERATION_MIN_INTERVAL: time::Duration = time::Duration::from_millis(32000);
+// alias lookups and public room directory pages are single cheap requests to one other server
+static DIRECTORY_DELAY: time::Duration = time::Duration::from_millis(2000);
+static DIRECTORY_MIN_INTERVAL: time::Duration = time::Duration::from_millis(200);
+pub static LOCAL_MIN_INTERVAL: time::Duration = time::Duration::from_millis(100);
+// a rate limited request is given up after that many attempts
+const MAX_RATE_LIMITED_ATTEMPTS: usize = 5;
+
+lazy_static! {
+    /// for joins, which make the homeserver talk to all other servers in the room
+    pub static ref FEDERATION: Pacer = Pacer::new(
+        ROOM_JOIN_DELAY,
+        FEDERATION_MIN_INTERVAL,
+        time::Duration::from_secs(30 * 60),
+    );
+    /// for alias lookups and public room directories, which the homeserver asks a single other server for
+    pub static ref DIRECTORY: Pacer = Pacer::new(
+        DIRECTORY_DELAY,
+        DIRECTORY_MIN_INTERVAL,
+        time::Duration::from_secs(10 * 60),
+    );
+    /// for requests the homeserver answers on its own, e.g. member lists and room state
+    pub static ref LOCAL: Pacer = Pacer::new(
+        ROOM_CRAWL_DELAY,
+        LOCAL_MIN_INTERVAL,
+        time::Duration::from_secs(5 * 60),
+    );
+    /// for messages posted in the rooms, which the homeserver rate limits on their own
+    pub static ref MESSAGES: Pacer = Pacer::new(
+        time::Duration::from_secs(5),
+        time::Duration::from_secs(1),
+        time::Duration::from_secs(10 * 60),
+    );
+    // ruma_client::Error doesn't give access to the response, only its debug output does.
+    // ruma-api only keeps the status code of failed responses,
+    // the body with the errcode and retry_after_ms is only there if a later version keeps it.
+    static ref RATE_LIMITED_PATTERN: regex::Regex =
+        regex::Regex::new(r#"StatusCode\(429\)|M_LIMIT_EXCEEDED|Too Many Requests"#).unwrap();
+    static ref RETRY_AFTER_PATTERN: regex::Regex =
+        regex::Regex::new(r#"retry_after_ms\\?"?\s*:\s*([0-9]+)"#).unwrap();
+}
+
+struct PacerState {
+    interval: time::Duration,
+    min_interval: time::Duration,
+    next_slot: time::Instant,
+}
+
+/// Spaces out requests that share a rate limit, even if they are sent from concurrent futures.
+/// The interval between two requests doubles whenever the server rate limits a request,
+/// and shrinks back towards the minimum interval with every request that gets through.
+pub struct Pacer {
+    max_interval: time::Duration,
+    state: Mutex<PacerState>,
+}
+
+impl Pacer {
+    fn new(interval: time::Duration, min_interval: time::Duration, max_interval: time::Duration) -> Self {
+        Pacer {
+            max_interval,
+            state: Mutex::new(PacerState {
+                interval,
+                min_interval,
+                next_slot: time::Instant::now(),
+            }),
+        }
+    }
+
+    /// sets the interval the pacer speeds up to as long as the server doesn't rate limit
+    pub fn set_min_interval(&self, min_interval: time::Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.min_interval = min_interval;
+        state.interval = cmp::max(state.interval, min_interval);
+    }
+
+    /// waits until the next free slot of this pacer
+    async fn acquire(&self) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let now = time::Instant::now();
+            let slot = cmp::max(state.next_slot, now);
+            state.next_slot = slot + state.interval;
+            slot - now
+        };
+        if wait > time::Duration::from_millis(0) {
+            Delay::new(wait).await.expect("wait failed");
+        }
+    }
+
+    fn speed_up(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.interval = cmp::max(state.min_interval, state.interval * 7 / 8);
+    }
+
+    fn back_off(&self, retry_after: time::Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.interval = cmp::min(self.max_interval, state.interval * 2);
+        let resume_at = time::Instant::now() + cmp::max(retry_after, state.interval);
+        state.next_slot = cmp::max(state.next_slot, resume_at);
+    }
+
+    /// Sends the request built by `request` in the next free slot,
+    /// and sends it again after backing off as long as the server rate limits it.
+    pub async fn request<T, F, R>(&self, request: F) -> Result<T, ruma_client::Error>
+    where
+        F: Fn() -> R,
+        R: Future<Output = Result<T, ruma_client::Error>>,
+    {
+        let mut attempts = 0;
+        loop {
+            self.acquire().await;
+            attempts += 1;
+            match request().await {
+                Ok(response) => {
+                    self.speed_up();
+                    return Ok(response);
+                },
+                Err(e) => match retry_after(&e) {
+                    Some(retry_after) if attempts < MAX_RATE_LIMITED_ATTEMPTS => {
+                        eprintln!("Rate limited, retrying in {:?} at the earliest.", retry_after);
+                        self.back_off(retry_after);
+                    },
+                    _ => return Err(e),
+                },
+            }
+        }
+    }
+}
+
+/// whether the server refused the request because of rate limiting
+pub fn is_rate_limited(error: &ruma_client::Error) -> bool {
+    retry_after(error).is_some()
+}
+
+/// how long the server asks to wait if the error is a rate limit, zero if it didn't say
+fn retry_after(error: &ruma_client::Error) -> Option<time::Duration> {
+    retry_after_in(&format!("{:?}", error))
+}
+
+/// retry_after of the debug output of an error
+fn retry_after_in(error: &str) -> Option<time::Duration> {
+    if !RATE_LIMITED_PATTERN.is_match(error) {
+        return None;
+    }
+    let retry_after_ms = RETRY_AFTER_PATTERN
+        .captures(error)
+        .and_then(|captures| captures[1].parse().ok())
+        .unwrap_or(0);
+    Some(time::Duration::from_millis(retry_after_ms))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // what Synapse answers to a rate limited request
+    static LIMIT_EXCEEDED_BODY: &str =
+        r#"{"errcode":"M_LIMIT_EXCEEDED","error":"Too Many Requests","retry_after_ms":2000}"#;
+
+    #[test]
+    fn retry_after_of_limit_exceeded_body() {
+        let error = format!("Error(Response({:?}))", LIMIT_EXCEEDED_BODY);
+        assert_eq!(retry_after_in(&error), Some(time::Duration::from_millis(2000)));
+    }
+
+    #[test]
+    fn retry_after_of_status_code_only() {
+        let error = "Error(RumaApi(Error(StatusCode(429))))";
+        assert_eq!(retry_after_in(error), Some(time::Duration::from_millis(0)));
+    }
+
+    #[test]
+    fn other_errors_are_no_rate_limits() {
+        assert_eq!(retry_after_in("Error(RumaApi(Error(StatusCode(404))))"), None);
+        let body = r#"{"errcode":"M_NOT_FOUND","error":"Room alias not found"}"#;
+        assert_eq!(retry_after_in(&format!("Error(Response({:?}))", body)), None);
+    }
+
+    #[test]
+    fn pacer_backs_off_and_speeds_up_to_its_minimum() {
+        let pacer = Pacer::new(
+            time::Duration::from_secs(2),
+            time::Duration::from_secs(1),
+            time::Duration::from_secs(3),
+        );
+        pacer.back_off(time::Duration::from_millis(0));
+        assert_eq!(pacer.state.lock().unwrap().interval, time::Duration::from_secs(3));
+        for _ in 0..20 {
+            pacer.speed_up();
+        }
+        assert_eq!(pacer.state.lock().unwrap().interval, time::Duration::from_secs(1));
+    }
+}